@@ -1,12 +1,20 @@
+use derive_more::Display;
 use num_enum::FromPrimitive;
 
-use crate::value::{Value, ValueArray};
+use crate::{
+    scanner::Span,
+    value::{Value, ValueArray},
+};
 
-#[allow(non_camel_case_types)]
+/// Largest constant index `OP_CONSTANT_LONG` can address with its 24-bit operand.
+pub(crate) const CONSTANT_LONG_MAX: usize = (1 << 24) - 1;
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, FromPrimitive)]
-pub enum OpCode {
+pub(crate) enum OpCode {
     OP_CONSTANT,
+    OP_CONSTANT_LONG,
     OP_NIL,
     OP_TRUE,
     OP_FALSE,
@@ -19,35 +27,745 @@ pub enum OpCode {
     OP_DIVIDE,
     OP_NOT,
     OP_NEGATE,
+    OP_PRINT,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_INVOKE,
+    OP_SUPER_INVOKE,
+    OP_CLOSURE,
+    OP_CAPTURE_LOCAL,
+    OP_CAPTURE_UPVALUE,
+    OP_CLOSE_UPVALUE,
+    OP_POP,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_GET_GLOBAL,
+    OP_DEFINE_GLOBAL,
+    OP_SET_GLOBAL,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_GET_PROPERTY,
+    OP_SET_PROPERTY,
+    OP_GET_SUPER,
     OP_RETURN,
+    OP_CLASS,
+    OP_INHERIT,
+    OP_METHOD,
+    OP_BUILD_LIST,
+    OP_BUILD_MAP,
+    OP_BUILD_STRING,
+    OP_GET_INDEX,
+    OP_SET_INDEX,
+    OP_GET_GLOBAL_LONG,
+    OP_DEFINE_GLOBAL_LONG,
+    OP_SET_GLOBAL_LONG,
+    OP_GET_PROPERTY_LONG,
+    OP_SET_PROPERTY_LONG,
+    OP_GET_SUPER_LONG,
+    OP_INVOKE_LONG,
+    OP_SUPER_INVOKE_LONG,
+    OP_CLOSURE_LONG,
+    OP_CLASS_LONG,
+    OP_METHOD_LONG,
 
     #[default]
     UNKNOWN,
 }
 
+use OpCode::*;
+
+/// A decoded instruction together with its operands. This is the only place
+/// that knows how instructions are laid out in `Chunk::code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Constant(u8),
+    /// Constant index as a 24-bit big-endian operand.
+    ConstantLong(u32),
+    Nil,
+    True,
+    False,
+    Equal,
+    Greater,
+    Less,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// Forward jump, relative to the end of the instruction.
+    Jump(u16),
+    JumpIfFalse(u16),
+    /// Backward jump, relative to the end of the instruction.
+    Loop(u16),
+    Call(u8),
+    Invoke {
+        name: u8,
+        arg_count: u8,
+    },
+    SuperInvoke {
+        name: u8,
+        arg_count: u8,
+    },
+    Closure(u8),
+    /// Each `Closure` is followed by one capture per upvalue of its function.
+    CaptureLocal(u8),
+    CaptureUpvalue(u8),
+    CloseUpvalue,
+    Pop,
+    GetLocal(u8),
+    SetLocal(u8),
+    GetGlobal(u8),
+    DefineGlobal(u8),
+    SetGlobal(u8),
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    GetProperty(u8),
+    SetProperty(u8),
+    GetSuper(u8),
+    Return,
+    Class(u8),
+    Inherit,
+    Method(u8),
+    /// Collects the given number of stack values into a new list.
+    BuildList(u8),
+    /// Collects the given number of key/value pairs into a new map.
+    BuildMap(u8),
+    /// Formats the given number of stack values and concatenates them.
+    BuildString(u8),
+    GetIndex,
+    SetIndex,
+    // Forms of the instructions above for constant indices past 255, with
+    // the same 24-bit operand as `ConstantLong`.
+    GetGlobalLong(u32),
+    DefineGlobalLong(u32),
+    SetGlobalLong(u32),
+    GetPropertyLong(u32),
+    SetPropertyLong(u32),
+    GetSuperLong(u32),
+    InvokeLong {
+        name: u32,
+        arg_count: u8,
+    },
+    SuperInvokeLong {
+        name: u32,
+        arg_count: u8,
+    },
+    ClosureLong(u32),
+    ClassLong(u32),
+    MethodLong(u32),
+}
+
+impl Instruction {
+    pub(crate) fn opcode(&self) -> OpCode {
+        use Instruction::*;
+        match self {
+            Constant(_) => OP_CONSTANT,
+            ConstantLong(_) => OP_CONSTANT_LONG,
+            Nil => OP_NIL,
+            True => OP_TRUE,
+            False => OP_FALSE,
+            Equal => OP_EQUAL,
+            Greater => OP_GREATER,
+            Less => OP_LESS,
+            Add => OP_ADD,
+            Subtract => OP_SUBTRACT,
+            Multiply => OP_MULTIPLY,
+            Divide => OP_DIVIDE,
+            Not => OP_NOT,
+            Negate => OP_NEGATE,
+            Print => OP_PRINT,
+            Jump(_) => OP_JUMP,
+            JumpIfFalse(_) => OP_JUMP_IF_FALSE,
+            Loop(_) => OP_LOOP,
+            Call(_) => OP_CALL,
+            Invoke { .. } => OP_INVOKE,
+            SuperInvoke { .. } => OP_SUPER_INVOKE,
+            Closure(_) => OP_CLOSURE,
+            CaptureLocal(_) => OP_CAPTURE_LOCAL,
+            CaptureUpvalue(_) => OP_CAPTURE_UPVALUE,
+            CloseUpvalue => OP_CLOSE_UPVALUE,
+            Pop => OP_POP,
+            GetLocal(_) => OP_GET_LOCAL,
+            SetLocal(_) => OP_SET_LOCAL,
+            GetGlobal(_) => OP_GET_GLOBAL,
+            DefineGlobal(_) => OP_DEFINE_GLOBAL,
+            SetGlobal(_) => OP_SET_GLOBAL,
+            GetUpvalue(_) => OP_GET_UPVALUE,
+            SetUpvalue(_) => OP_SET_UPVALUE,
+            GetProperty(_) => OP_GET_PROPERTY,
+            SetProperty(_) => OP_SET_PROPERTY,
+            GetSuper(_) => OP_GET_SUPER,
+            Return => OP_RETURN,
+            Class(_) => OP_CLASS,
+            Inherit => OP_INHERIT,
+            Method(_) => OP_METHOD,
+            BuildList(_) => OP_BUILD_LIST,
+            BuildMap(_) => OP_BUILD_MAP,
+            BuildString(_) => OP_BUILD_STRING,
+            GetIndex => OP_GET_INDEX,
+            SetIndex => OP_SET_INDEX,
+            GetGlobalLong(_) => OP_GET_GLOBAL_LONG,
+            DefineGlobalLong(_) => OP_DEFINE_GLOBAL_LONG,
+            SetGlobalLong(_) => OP_SET_GLOBAL_LONG,
+            GetPropertyLong(_) => OP_GET_PROPERTY_LONG,
+            SetPropertyLong(_) => OP_SET_PROPERTY_LONG,
+            GetSuperLong(_) => OP_GET_SUPER_LONG,
+            InvokeLong { .. } => OP_INVOKE_LONG,
+            SuperInvokeLong { .. } => OP_SUPER_INVOKE_LONG,
+            ClosureLong(_) => OP_CLOSURE_LONG,
+            ClassLong(_) => OP_CLASS_LONG,
+            MethodLong(_) => OP_METHOD_LONG,
+        }
+    }
+
+    /// Writes the opcode and operands into `bytes`, returning how many bytes
+    /// the instruction takes.
+    fn encode(&self, bytes: &mut [u8; 5]) -> usize {
+        use Instruction::*;
+        bytes[0] = self.opcode() as u8;
+        match *self {
+            Constant(operand)
+            | Call(operand)
+            | Closure(operand)
+            | CaptureLocal(operand)
+            | CaptureUpvalue(operand)
+            | GetLocal(operand)
+            | SetLocal(operand)
+            | GetGlobal(operand)
+            | DefineGlobal(operand)
+            | SetGlobal(operand)
+            | GetUpvalue(operand)
+            | SetUpvalue(operand)
+            | GetProperty(operand)
+            | SetProperty(operand)
+            | GetSuper(operand)
+            | Class(operand)
+            | Method(operand)
+            | BuildList(operand)
+            | BuildMap(operand)
+            | BuildString(operand) => {
+                bytes[1] = operand;
+                2
+            }
+            ConstantLong(index)
+            | GetGlobalLong(index)
+            | DefineGlobalLong(index)
+            | SetGlobalLong(index)
+            | GetPropertyLong(index)
+            | SetPropertyLong(index)
+            | GetSuperLong(index)
+            | ClosureLong(index)
+            | ClassLong(index)
+            | MethodLong(index) => {
+                bytes[1..4].copy_from_slice(&index.to_be_bytes()[1..]);
+                4
+            }
+            Jump(offset) | JumpIfFalse(offset) | Loop(offset) => {
+                bytes[1..3].copy_from_slice(&offset.to_be_bytes());
+                3
+            }
+            Invoke { name, arg_count } | SuperInvoke { name, arg_count } => {
+                bytes[1] = name;
+                bytes[2] = arg_count;
+                3
+            }
+            InvokeLong { name, arg_count } | SuperInvokeLong { name, arg_count } => {
+                bytes[1..4].copy_from_slice(&name.to_be_bytes()[1..]);
+                bytes[4] = arg_count;
+                5
+            }
+            Nil | True | False | Equal | Greater | Less | Add | Subtract | Multiply | Divide
+            | Not | Negate | Print | CloseUpvalue | Pop | Return | Inherit | GetIndex
+            | SetIndex => 1,
+        }
+    }
+
+    /// Number of bytes the encoded instruction takes.
+    pub fn size(&self) -> usize {
+        self.encode(&mut [0; 5])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum DecodeError {
+    #[display(fmt = "Unknown opcode {} at offset {}.", byte, offset)]
+    UnknownOpcode { offset: usize, byte: u8 },
+    #[display(fmt = "Truncated instruction at offset {}.", offset)]
+    Truncated { offset: usize },
+}
+
+/// Walks a chunk's code one instruction at a time, yielding each instruction
+/// with its offset. Stops after the first decode error.
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = Result<(usize, Instruction), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.chunk.code.len() {
+            return None;
+        }
+
+        let offset = self.offset;
+        match self.chunk.decode(offset) {
+            Ok(instruction) => {
+                self.offset += instruction.size();
+                Some(Ok((offset, instruction)))
+            }
+            Err(error) => {
+                self.offset = self.chunk.code.len();
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// A run of bytecode starting at `offset` that was all compiled from `line`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LineStart {
+    offset: u32,
+    line: u32,
+}
+
+/// A run of bytecode starting at `offset` that was all compiled from the
+/// `len` bytes of source at `start`, beginning at `column` of its line.
+#[derive(Debug, Clone, Copy, Default)]
+struct SpanStart {
+    offset: u32,
+    start: u32,
+    len: u32,
+    column: u32,
+}
+
+impl SpanStart {
+    /// Appends this run to `out` as four LEB128 numbers: the offset and
+    /// source start relative to `previous` (the start zigzag encoded, since
+    /// it can go backwards), then the length and column. Runs rarely span
+    /// more than one instruction, so this usually takes four bytes.
+    fn encode(&self, previous: &SpanStart, out: &mut Vec<u8>) {
+        let start = i64::from(self.start) - i64::from(previous.start);
+        write_leb128(out, u64::from(self.offset - previous.offset));
+        write_leb128(out, ((start << 1) ^ (start >> 63)) as u64);
+        write_leb128(out, u64::from(self.len));
+        write_leb128(out, u64::from(self.column));
+    }
+
+    /// Reads the run that `encode` wrote at `bytes[*pos..]`.
+    fn decode(previous: &SpanStart, bytes: &[u8], pos: &mut usize) -> SpanStart {
+        let offset = read_leb128(bytes, pos);
+        let start = read_leb128(bytes, pos);
+        let start = (start >> 1) as i64 ^ -((start & 1) as i64);
+        SpanStart {
+            offset: previous.offset + offset as u32,
+            start: (i64::from(previous.start) + start) as u32,
+            len: read_leb128(bytes, pos) as u32,
+            column: read_leb128(bytes, pos) as u32,
+        }
+    }
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_leb128(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+/// The bytecode of one function, read as typed `Instruction`s.
 #[derive(Debug)]
 pub struct Chunk {
-    pub code: Vec<u8>,
-    pub lines: Vec<usize>,
-    pub constants: ValueArray,
+    pub(crate) code: Vec<u8>,
+    /// Run-length encoded source lines, ordered by offset.
+    pub(crate) lines: Vec<LineStart>,
+    /// Run-length encoded source spans, ordered by offset and packed by
+    /// `SpanStart::encode`. Their lines are in `lines`.
+    pub(crate) spans: Vec<u8>,
+    /// The most recent span run, which new bytes either extend or follow.
+    last_span: Option<SpanStart>,
+    pub(crate) constants: ValueArray,
 }
 
 impl Chunk {
-    pub fn new() -> Chunk {
+    pub(crate) fn new() -> Chunk {
         Chunk {
             code: vec![],
             lines: vec![],
+            spans: vec![],
+            last_span: None,
             constants: ValueArray::new(),
         }
     }
 
-    pub fn write(&mut self, byte: u8, line: usize) {
+    pub(crate) fn write(&mut self, byte: u8, span: Span) {
         self.code.push(byte);
-        self.lines.push(line);
+        let offset = (self.code.len() - 1) as u32;
+
+        let line = span.line as u32;
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineStart { offset, line });
+        }
+
+        let run = SpanStart {
+            offset,
+            start: span.start as u32,
+            len: (span.end - span.start) as u32,
+            column: span.column as u32,
+        };
+        let new_span = |last: &SpanStart| {
+            (last.start, last.len, last.column) != (run.start, run.len, run.column)
+        };
+        if self.last_span.as_ref().is_none_or(new_span) {
+            run.encode(&self.last_span.unwrap_or_default(), &mut self.spans);
+            self.last_span = Some(run);
+        }
+    }
+
+    /// Appends `instruction`, returning the offset it starts at.
+    pub(crate) fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        let offset = self.code.len();
+        let mut bytes = [0; 5];
+        let size = instruction.encode(&mut bytes);
+        for &byte in &bytes[..size] {
+            self.write(byte, span);
+        }
+        offset
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    /// Rewrites the forward jump starting at `offset` so it lands on
+    /// `target`. Returns false if the distance doesn't fit in its operand.
+    pub(crate) fn patch_jump(&mut self, offset: usize, target: usize) -> bool {
+        let jump: fn(u16) -> Instruction = match self.decode(offset) {
+            Ok(Instruction::Jump(_)) => Instruction::Jump,
+            Ok(Instruction::JumpIfFalse(_)) => Instruction::JumpIfFalse,
+            other => panic!("expected a jump at offset {}, found {:?}", offset, other),
+        };
+        let Ok(distance) = u16::try_from(target - offset - jump(0).size()) else {
+            return false;
+        };
+
+        let mut bytes = [0; 5];
+        let size = jump(distance).encode(&mut bytes);
+        self.code[offset..offset + size].copy_from_slice(&bytes[..size]);
+        true
+    }
+
+    /// Decodes the instruction starting at `offset`.
+    pub fn decode(&self, offset: usize) -> Result<Instruction, DecodeError> {
+        use Instruction::*;
+        let byte = |i: usize| {
+            self.code
+                .get(offset + i)
+                .copied()
+                .ok_or(DecodeError::Truncated { offset })
+        };
+        let short = |i: usize| Ok(u16::from_be_bytes([byte(i)?, byte(i + 1)?]));
+        let long = |i: usize| Ok((u32::from(byte(i)?) << 16) | u32::from(short(i + 1)?));
+
+        let instruction = match OpCode::from(byte(0)?) {
+            OP_CONSTANT => Constant(byte(1)?),
+            OP_CONSTANT_LONG => ConstantLong(long(1)?),
+            OP_NIL => Nil,
+            OP_TRUE => True,
+            OP_FALSE => False,
+            OP_EQUAL => Equal,
+            OP_GREATER => Greater,
+            OP_LESS => Less,
+            OP_ADD => Add,
+            OP_SUBTRACT => Subtract,
+            OP_MULTIPLY => Multiply,
+            OP_DIVIDE => Divide,
+            OP_NOT => Not,
+            OP_NEGATE => Negate,
+            OP_PRINT => Print,
+            OP_JUMP => Jump(short(1)?),
+            OP_JUMP_IF_FALSE => JumpIfFalse(short(1)?),
+            OP_LOOP => Loop(short(1)?),
+            OP_CALL => Call(byte(1)?),
+            OP_INVOKE => Invoke {
+                name: byte(1)?,
+                arg_count: byte(2)?,
+            },
+            OP_SUPER_INVOKE => SuperInvoke {
+                name: byte(1)?,
+                arg_count: byte(2)?,
+            },
+            OP_CLOSURE => Closure(byte(1)?),
+            OP_CAPTURE_LOCAL => CaptureLocal(byte(1)?),
+            OP_CAPTURE_UPVALUE => CaptureUpvalue(byte(1)?),
+            OP_CLOSE_UPVALUE => CloseUpvalue,
+            OP_POP => Pop,
+            OP_GET_LOCAL => GetLocal(byte(1)?),
+            OP_SET_LOCAL => SetLocal(byte(1)?),
+            OP_GET_GLOBAL => GetGlobal(byte(1)?),
+            OP_DEFINE_GLOBAL => DefineGlobal(byte(1)?),
+            OP_SET_GLOBAL => SetGlobal(byte(1)?),
+            OP_GET_UPVALUE => GetUpvalue(byte(1)?),
+            OP_SET_UPVALUE => SetUpvalue(byte(1)?),
+            OP_GET_PROPERTY => GetProperty(byte(1)?),
+            OP_SET_PROPERTY => SetProperty(byte(1)?),
+            OP_GET_SUPER => GetSuper(byte(1)?),
+            OP_RETURN => Return,
+            OP_CLASS => Class(byte(1)?),
+            OP_INHERIT => Inherit,
+            OP_METHOD => Method(byte(1)?),
+            OP_BUILD_LIST => BuildList(byte(1)?),
+            OP_BUILD_MAP => BuildMap(byte(1)?),
+            OP_BUILD_STRING => BuildString(byte(1)?),
+            OP_GET_INDEX => GetIndex,
+            OP_SET_INDEX => SetIndex,
+            OP_GET_GLOBAL_LONG => GetGlobalLong(long(1)?),
+            OP_DEFINE_GLOBAL_LONG => DefineGlobalLong(long(1)?),
+            OP_SET_GLOBAL_LONG => SetGlobalLong(long(1)?),
+            OP_GET_PROPERTY_LONG => GetPropertyLong(long(1)?),
+            OP_SET_PROPERTY_LONG => SetPropertyLong(long(1)?),
+            OP_GET_SUPER_LONG => GetSuperLong(long(1)?),
+            OP_INVOKE_LONG => InvokeLong {
+                name: long(1)?,
+                arg_count: byte(4)?,
+            },
+            OP_SUPER_INVOKE_LONG => SuperInvokeLong {
+                name: long(1)?,
+                arg_count: byte(4)?,
+            },
+            OP_CLOSURE_LONG => ClosureLong(long(1)?),
+            OP_CLASS_LONG => ClassLong(long(1)?),
+            OP_METHOD_LONG => MethodLong(long(1)?),
+            UNKNOWN => {
+                return Err(DecodeError::UnknownOpcode {
+                    offset,
+                    byte: self.code[offset],
+                })
+            }
+        };
+        Ok(instruction)
+    }
+
+    /// Length of the bytecode in bytes.
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// The constant table that instruction operands index into.
+    pub fn constants(&self) -> &[Value] {
+        &self.constants.values
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset: 0,
+        }
+    }
+
+    /// The source span of the instruction byte at `offset`. Spans are packed
+    /// for size, so this scans them from the start; it is meant for reporting
+    /// errors, not for every instruction.
+    pub fn get_span(&self, offset: usize) -> Span {
+        let mut run = SpanStart::default();
+        let mut pos = 0;
+        while pos < self.spans.len() {
+            let next = SpanStart::decode(&run, &self.spans, &mut pos);
+            if next.offset as usize > offset {
+                break;
+            }
+            run = next;
+        }
+        Span {
+            start: run.start as usize,
+            end: (run.start + run.len) as usize,
+            line: self.get_line(offset),
+            column: run.column as usize,
+        }
+    }
+
+    /// The source line of the instruction byte at `offset`.
+    pub fn get_line(&self, offset: usize) -> usize {
+        let run = self
+            .lines
+            .partition_point(|start| start.offset as usize <= offset);
+        self.lines[run - 1].line as usize
+    }
+
+    /// Appends `value` to the constant table, returning its index.
+    pub(crate) fn add_constant(&mut self, value: Value) -> usize {
         self.constants.write(value);
-        (self.constants.values.len() - 1) as u8
+        self.constants.values.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions_round_trip() {
+        let program = [
+            Instruction::Constant(7),
+            Instruction::ConstantLong(0x01_02_03),
+            Instruction::Jump(0x1234),
+            Instruction::Invoke {
+                name: 3,
+                arg_count: 2,
+            },
+            Instruction::Closure(1),
+            Instruction::CaptureLocal(0),
+            Instruction::CaptureUpvalue(4),
+            Instruction::GetGlobalLong(0x0f_ff_ff),
+            Instruction::SuperInvokeLong {
+                name: 0x01_00_00,
+                arg_count: 9,
+            },
+            Instruction::Return,
+        ];
+        let mut chunk = Chunk::new();
+        let offsets: Vec<usize> = program
+            .iter()
+            .map(|&i| chunk.emit(i, Span::default()))
+            .collect();
+
+        let decoded: Vec<(usize, Instruction)> = chunk.instructions().map(Result::unwrap).collect();
+        let expected: Vec<(usize, Instruction)> = offsets.into_iter().zip(program).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn unknown_opcode_is_a_decode_error() {
+        let mut chunk = Chunk::new();
+        chunk.emit(Instruction::Nil, Span::default());
+        chunk.write(OpCode::UNKNOWN as u8, Span::default());
+        chunk.emit(Instruction::Return, Span::default());
+
+        let decoded: Vec<_> = chunk.instructions().collect();
+        assert_eq!(
+            decoded,
+            [
+                Ok((0, Instruction::Nil)),
+                Err(DecodeError::UnknownOpcode {
+                    offset: 1,
+                    byte: OpCode::UNKNOWN as u8
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_operand_is_a_decode_error() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::OP_JUMP as u8, Span::default());
+        chunk.write(0, Span::default());
+
+        assert_eq!(chunk.decode(0), Err(DecodeError::Truncated { offset: 0 }));
+    }
+
+    #[test]
+    fn patch_jump_re_encodes_the_offset() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.emit(Instruction::JumpIfFalse(0xffff), Span::default());
+        chunk.emit(Instruction::Pop, Span::default());
+        assert!(chunk.patch_jump(jump, chunk.len()));
+        assert_eq!(chunk.decode(jump), Ok(Instruction::JumpIfFalse(1)));
+
+        let jump = chunk.emit(Instruction::Jump(0xffff), Span::default());
+        assert!(!chunk.patch_jump(jump, jump + 3 + 0x10000));
+    }
+
+    #[test]
+    fn get_line_follows_runs() {
+        let mut chunk = Chunk::new();
+        for (byte, line) in [(0, 1), (1, 1), (2, 2), (3, 2), (4, 2), (5, 4), (6, 1)] {
+            let span = Span {
+                line,
+                ..Span::default()
+            };
+            chunk.write(byte, span);
+        }
+
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|i| chunk.get_line(i)).collect();
+        assert_eq!(lines, [1, 1, 2, 2, 2, 4, 1]);
+        assert_eq!(chunk.lines.len(), 4);
+        assert_eq!(chunk.spans.len(), 4);
+    }
+
+    #[test]
+    fn get_span_follows_runs() {
+        let span = |start, end, line, column| Span {
+            start,
+            end,
+            line,
+            column,
+        };
+        let spans = [
+            span(0, 5, 1, 1),
+            span(0, 5, 1, 1),
+            span(6, 7, 1, 7),
+            span(9, 12, 2, 1),
+            span(9, 12, 2, 1),
+            span(13, 13, 2, 5),
+        ];
+        let mut chunk = Chunk::new();
+        for span in spans {
+            chunk.write(0, span);
+        }
+
+        let decoded: Vec<Span> = (0..chunk.code.len()).map(|i| chunk.get_span(i)).collect();
+        assert_eq!(decoded, spans);
+        assert_eq!(chunk.lines.len(), 2);
+        // Four runs of four bytes each.
+        assert_eq!(chunk.spans.len(), 16);
+    }
+
+    #[test]
+    fn span_runs_survive_large_values() {
+        let spans = [
+            Span {
+                start: 100_000,
+                end: 100_300,
+                line: 5000,
+                column: 200,
+            },
+            Span {
+                start: 3,
+                end: 4,
+                line: 1,
+                column: 4,
+            },
+        ];
+        let mut chunk = Chunk::new();
+        for span in spans {
+            for _ in 0..200 {
+                chunk.write(0, span);
+            }
+        }
+
+        assert_eq!(chunk.get_span(0), spans[0]);
+        assert_eq!(chunk.get_span(199), spans[0]);
+        assert_eq!(chunk.get_span(200), spans[1]);
+        assert_eq!(chunk.get_span(399), spans[1]);
     }
 }
@@ -1,23 +1,121 @@
-use std::cell::Cell;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
 
 use crate::{
-    chunk::{Chunk, OpCode::*},
-    object::Object,
-    scanner::{Scanner, Token, TokenType},
+    chunk::{Chunk, Instruction, CONSTANT_LONG_MAX},
+    debug::disassemble_chunk,
+    diagnostic::Diagnostic,
+    memory::{Heap, ObjRef},
+    object::{ObjFunction, Object},
+    scanner::{decode_string, Scanner, Span, Token, TokenType},
     value::Value,
 };
 use enum_iterator::Sequence;
 
-pub struct Compiler {
-    scanner: Scanner,
-    current_chunk: Chunk,
-    current: Token,
-    previous: Token,
+pub struct Compiler<'a> {
+    heap: &'a mut Heap,
+    /// Marks the roots owned by the VM when a collection runs mid-compile.
+    vm_roots: &'a dyn Fn(&mut Heap),
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    diagnostics: RefCell<Vec<Diagnostic>>,
     had_error: Cell<bool>,
     panic_mode: Cell<bool>,
+    /// Disassemble each function once it compiles.
+    print_code: bool,
+    states: Vec<FunctionCompiler<'a>>,
+    classes: Vec<ClassCompiler>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Per-function compilation state. The innermost function being compiled is
+/// the last entry of `Compiler::states`.
+struct FunctionCompiler<'a> {
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: i32,
+    /// Where each constant already in the chunk lives, so repeats share a
+    /// slot. Only needed while compiling, so it isn't kept in the chunk.
+    constants: HashMap<ConstantKey, usize>,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn new(function_type: FunctionType, name: Option<String>) -> FunctionCompiler<'a> {
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+        // Slot zero holds the function being called, or the receiver in methods.
+        let slot_name = match function_type {
+            FunctionType::Method | FunctionType::Initializer => Token::synthetic("this"),
+            FunctionType::Function | FunctionType::Script => Token::none(),
+        };
+        locals.push(Local {
+            name: slot_name,
+            depth: 0,
+            is_captured: false,
+        });
+
+        FunctionCompiler {
+            function: ObjFunction::new(name),
+            function_type,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+            constants: HashMap::new(),
+        }
+    }
+}
+
+/// Identity of a constant for deduplication. Numbers compare by bits so
+/// `0` and `-0` stay distinct; strings are interned, so handles suffice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Object(ObjRef),
+}
+
+impl ConstantKey {
+    fn of(value: Value) -> Option<ConstantKey> {
+        if value.is_number() {
+            Some(ConstantKey::Number(value.as_number_unchecked().to_bits()))
+        } else if value.is_object() {
+            Some(ConstantKey::Object(value.as_object()))
+        } else {
+            None
+        }
+    }
+}
+
+struct Local<'a> {
+    name: Token<'a>,
+    depth: i32,
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
 }
 
-type ParseFn = fn(&mut Compiler) -> ();
+/// Per-class compilation state, innermost class last.
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+type ParseFn = fn(&mut Compiler, bool);
 
 struct ParseRule {
     prefix: Option<ParseFn>,
@@ -56,6 +154,19 @@ macro_rules! rule {
     };
 }
 
+/// Picks the one-byte form of an instruction that indexes the constant
+/// table, or its `Long` form once the index no longer fits.
+fn constant_op(
+    index: u32,
+    short: impl FnOnce(u8) -> Instruction,
+    long: impl FnOnce(u32) -> Instruction,
+) -> Instruction {
+    match u8::try_from(index) {
+        Ok(index) => short(index),
+        Err(_) => long(index),
+    }
+}
+
 #[repr(u8)]
 #[derive(Clone, Copy, PartialEq, PartialOrd, Sequence)]
 enum Precedence {
@@ -72,20 +183,37 @@ enum Precedence {
     Primary,
 }
 
-impl Compiler {
-    fn new<'a>(scanner: Scanner, chunk: Chunk) -> Compiler {
+impl<'a> Compiler<'a> {
+    fn new(
+        scanner: Scanner<'a>,
+        heap: &'a mut Heap,
+        vm_roots: &'a dyn Fn(&mut Heap),
+    ) -> Compiler<'a> {
         Compiler {
+            heap,
+            vm_roots,
             scanner,
-            current_chunk: chunk,
             current: Token::none(),
             previous: Token::none(),
+            diagnostics: RefCell::new(vec![]),
             had_error: Cell::new(false),
             panic_mode: Cell::new(false),
+            print_code: false,
+            states: vec![FunctionCompiler::new(FunctionType::Script, None)],
+            classes: vec![],
         }
     }
 
+    fn state(&self) -> &FunctionCompiler<'a> {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionCompiler<'a> {
+        self.states.last_mut().unwrap()
+    }
+
     fn current_chunk(&mut self) -> &mut Chunk {
-        &mut self.current_chunk
+        &mut self.state_mut().function.chunk
     }
 
     fn error_at(&self, token: &Token, message: &str) {
@@ -93,17 +221,9 @@ impl Compiler {
             return;
         }
         self.panic_mode.set(true);
-        eprint!("[line {}] Error", token.line);
-
-        if token.token_type == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.token_type == TokenType::Error {
-            // no-op
-        } else {
-            eprint!(" at '{}'", token.lexeme);
-        }
-
-        eprintln!(": {}", message);
+        self.diagnostics
+            .borrow_mut()
+            .push(Diagnostic::error(message, token.span));
         self.had_error.set(true);
     }
 
@@ -125,7 +245,7 @@ impl Compiler {
                 break;
             }
 
-            self.error_at_current(&self.current.lexeme);
+            self.error_at_current(self.current.lexeme);
         }
     }
 
@@ -138,37 +258,271 @@ impl Compiler {
         self.error_at_current(message);
     }
 
-    pub fn end_compiler(&mut self) {
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn end_compiler(&mut self) -> FunctionCompiler<'a> {
         self.emit_return();
+        let state = self.states.pop().unwrap();
+
+        if self.print_code && !self.had_error.get() {
+            let name = state.function.name.as_deref().unwrap_or("<script>");
+            disassemble_chunk(self.heap, &state.function.chunk, name);
+        }
+
+        state
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.emit_at(instruction, self.previous.span);
     }
 
-    fn emit_byte(&mut self, byte: u8) {
-        let line = self.previous.line as usize;
-        self.current_chunk().write(byte, line);
+    /// Emits `instruction` attributed to `span` rather than the previous
+    /// token, so runtime errors point at the operator or name involved.
+    fn emit_at(&mut self, instruction: Instruction, span: Span) {
+        self.current_chunk().emit(instruction, span);
     }
 
-    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
+    fn emit_loop(&mut self, loop_start: usize) {
+        let offset = self.current_chunk().code.len() + Instruction::Loop(0).size() - loop_start;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        self.emit(Instruction::Loop(offset as u16));
+    }
+
+    /// Emits a jump with a placeholder offset and returns where the jump
+    /// starts, for `patch_jump`.
+    fn emit_jump(&mut self, instruction: fn(u16) -> Instruction) -> usize {
+        let offset = self.current_chunk().len();
+        self.emit(instruction(0xffff));
+        offset
+    }
+
+    /// Points the jump at `offset` at the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) {
+        let target = self.current_chunk().len();
+        if !self.current_chunk().patch_jump(offset, target) {
+            self.error("Too much code to jump over.");
+        }
     }
 
     fn emit_return(&mut self) {
-        self.emit_byte(OP_RETURN as u8);
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit(Instruction::GetLocal(0));
+        } else {
+            self.emit(Instruction::Nil);
+        }
+        self.emit(Instruction::Return);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.current_chunk().add_constant(value);
-        if constant > u8::MAX {
+    fn make_constant(&mut self, value: Value) -> u32 {
+        let key = ConstantKey::of(value);
+        let existing = key.and_then(|key| self.state().constants.get(&key).copied());
+        let constant = match existing {
+            Some(constant) => constant,
+            None => {
+                let constant = self.current_chunk().add_constant(value);
+                if let Some(key) = key {
+                    self.state_mut().constants.insert(key, constant);
+                }
+                constant
+            }
+        };
+        if constant > CONSTANT_LONG_MAX {
             self.error("Too many constants in one chunk.");
             return 0;
         }
 
-        constant
+        constant as u32
+    }
+
+    /// Splits out the heap together with a closure marking every root that
+    /// must survive a collection triggered mid-compile.
+    fn heap_with_roots(&mut self) -> (&mut Heap, impl Fn(&mut Heap) + '_) {
+        let vm_roots = self.vm_roots;
+        let states = &self.states;
+        let roots = move |heap: &mut Heap| {
+            vm_roots(heap);
+            for state in states {
+                for &constant in &state.function.chunk.constants.values {
+                    heap.mark_value(constant);
+                }
+            }
+        };
+        (&mut *self.heap, roots)
+    }
+
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        let (heap, roots) = self.heap_with_roots();
+        heap.alloc(object, &roots)
+    }
+
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        let (heap, roots) = self.heap_with_roots();
+        heap.intern(chars, &roots)
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> u32 {
+        let string = self.intern(name.lexeme);
+        self.make_constant(Value::object(string))
+    }
+
+    fn resolve_local(&self, state: usize, name: &Token) -> Option<u8> {
+        for (i, local) in self.states[state].locals.iter().enumerate().rev() {
+            if local.name.lexeme == name.lexeme {
+                if local.depth == -1 {
+                    self.error("Can't read local variable in its own initializer.");
+                }
+                return Some(i as u8);
+            }
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.states[state].upvalues;
+        if let Some(i) = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return i as u8;
+        }
+
+        if upvalues.len() == UINT8_COUNT {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let state = &mut self.states[state];
+        state.upvalues.push(Upvalue { index, is_local });
+        state.function.upvalue_count = state.upvalues.len();
+        (state.upvalues.len() - 1) as u8
     }
 
+    fn resolve_upvalue(&mut self, state: usize, name: &Token) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+
+        let enclosing = state - 1;
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.states[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(state, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_local(&mut self, name: Token<'a>) {
+        if self.state().locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.state_mut().locals.push(Local {
+            name,
+            depth: -1,
+            is_captured: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        if self.state().scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous;
+        let state = self.state();
+        for local in state.locals.iter().rev() {
+            if local.depth != -1 && local.depth < state.scope_depth {
+                break;
+            }
+
+            if local.name.lexeme == name.lexeme {
+                self.error("Already a variable with this name in this scope.");
+            }
+        }
+
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> u32 {
+        self.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.previous;
+        self.identifier_constant(&name)
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        state.locals.last_mut().unwrap().depth = state.scope_depth;
+    }
+
+    fn define_variable(&mut self, global: u32) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit(constant_op(
+            global,
+            Instruction::DefineGlobal,
+            Instruction::DefineGlobalLong,
+        ));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error("Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
+    }
+
+    /// Loads `value`, falling back to `ConstantLong` once the constant
+    /// table no longer fits in a byte.
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OP_CONSTANT as u8, constant);
+        self.emit(constant_op(
+            constant,
+            Instruction::Constant,
+            Instruction::ConstantLong,
+        ));
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
@@ -179,12 +533,17 @@ impl Compiler {
             return;
         };
 
-        prefix_rule(self);
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign);
 
         while precedence <= self.get_rule(self.current.token_type).precedence {
             self.advance();
             let infix_rule = self.get_rule(self.previous.token_type).infix;
-            infix_rule.unwrap()(self);
+            infix_rule.unwrap()(self, can_assign);
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
         }
     }
 
@@ -192,8 +551,341 @@ impl Compiler {
         self.parse_precedence(Precedence::Assignment);
     }
 
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode.get() {
+            self.synchronize();
+        }
+    }
+
+    /// Leaves panic mode by skipping tokens up to a likely statement
+    /// boundary, so errors after it are reported too.
+    fn synchronize(&mut self) {
+        self.panic_mode.set(false);
+
+        while self.current.token_type != TokenType::Eof {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.previous.lexeme.to_owned();
+        self.states
+            .push(FunctionCompiler::new(function_type, Some(name)));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.state().function.arity == u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                } else {
+                    self.state_mut().function.arity += 1;
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let state = self.end_compiler();
+        let function = self.alloc(Object::Function(state.function));
+        let constant = self.make_constant(Value::object(function));
+        self.emit(constant_op(
+            constant,
+            Instruction::Closure,
+            Instruction::ClosureLong,
+        ));
+
+        for upvalue in state.upvalues {
+            self.emit(if upvalue.is_local {
+                Instruction::CaptureLocal(upvalue.index)
+            } else {
+                Instruction::CaptureUpvalue(upvalue.index)
+            });
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous;
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit(constant_op(
+            constant,
+            Instruction::Method,
+            Instruction::MethodLong,
+        ));
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit(constant_op(
+            name_constant,
+            Instruction::Class,
+            Instruction::ClassLong,
+        ));
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.lexeme == self.previous.lexeme {
+                self.error("A class can't inherit from itself.");
+            }
+
+            self.begin_scope();
+            self.add_local(Token::synthetic("super"));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit(Instruction::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit(Instruction::Pop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit(Instruction::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        while let Some(local) = self
+            .state()
+            .locals
+            .last()
+            .filter(|local| local.depth > self.state().scope_depth)
+        {
+            if local.is_captured {
+                self.emit(Instruction::CloseUpvalue);
+            } else {
+                self.emit(Instruction::Pop);
+            }
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit(Instruction::Print);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit(Instruction::Pop);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(Instruction::JumpIfFalse));
+            self.emit(Instruction::Pop); // Condition.
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(Instruction::Jump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit(Instruction::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit(Instruction::Pop); // Condition.
+        }
+
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(Instruction::JumpIfFalse);
+        self.emit(Instruction::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(Instruction::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit(Instruction::Pop);
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit(Instruction::Return);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(Instruction::JumpIfFalse);
+        self.emit(Instruction::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit(Instruction::Pop);
+    }
+
     fn binary(&mut self) {
         let operator_type = self.previous.token_type;
+        let span = self.previous.span;
 
         let rule = self.get_rule(operator_type);
         self.parse_precedence(rule.precedence.next().unwrap());
@@ -201,37 +893,143 @@ impl Compiler {
         use TokenType::*;
         match operator_type {
             BangEqual => {
-                self.emit_bytes(OP_EQUAL as u8, OP_NOT as u8);
+                self.emit_at(Instruction::Equal, span);
+                self.emit_at(Instruction::Not, span);
             }
             EqualEqual => {
-                self.emit_byte(OP_EQUAL as u8);
+                self.emit_at(Instruction::Equal, span);
             }
             Greater => {
-                self.emit_byte(OP_GREATER as u8);
+                self.emit_at(Instruction::Greater, span);
             }
             GreaterEqual => {
-                self.emit_bytes(OP_LESS as u8, OP_NOT as u8);
+                self.emit_at(Instruction::Less, span);
+                self.emit_at(Instruction::Not, span);
             }
             Less => {
-                self.emit_byte(OP_LESS as u8);
+                self.emit_at(Instruction::Less, span);
             }
             LessEqual => {
-                self.emit_bytes(OP_GREATER as u8, OP_NOT as u8);
+                self.emit_at(Instruction::Greater, span);
+                self.emit_at(Instruction::Not, span);
             }
-            Plus => self.emit_byte(OP_ADD as u8),
-            Minus => self.emit_byte(OP_SUBTRACT as u8),
-            Star => self.emit_byte(OP_MULTIPLY as u8),
-            Slash => self.emit_byte(OP_DIVIDE as u8),
+            Plus => self.emit_at(Instruction::Add, span),
+            Minus => self.emit_at(Instruction::Subtract, span),
+            Star => self.emit_at(Instruction::Multiply, span),
+            Slash => self.emit_at(Instruction::Divide, span),
             _ => unreachable!(),
         }
     }
 
+    fn and(&mut self) {
+        let end_jump = self.emit_jump(Instruction::JumpIfFalse);
+
+        self.emit(Instruction::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(Instruction::JumpIfFalse);
+        let end_jump = self.emit_jump(Instruction::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit(Instruction::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self) {
+        let span = self.previous.span;
+        let arg_count = self.argument_list();
+        self.emit_at(Instruction::Call(arg_count), span);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous;
+        let span = name.span;
+        let name = self.identifier_constant(&name);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            let op = constant_op(name, Instruction::SetProperty, Instruction::SetPropertyLong);
+            self.emit_at(op, span);
+        } else if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            let op = constant_op(
+                name,
+                |name| Instruction::Invoke { name, arg_count },
+                |name| Instruction::InvokeLong { name, arg_count },
+            );
+            self.emit_at(op, span);
+        } else {
+            let op = constant_op(name, Instruction::GetProperty, Instruction::GetPropertyLong);
+            self.emit_at(op, span);
+        }
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        let span = self.previous.span;
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_at(Instruction::SetIndex, span);
+        } else {
+            self.emit_at(Instruction::GetIndex, span);
+        }
+    }
+
+    fn list(&mut self) {
+        let mut item_count: u8 = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if item_count == u8::MAX {
+                    self.error("Can't have more than 255 items in a list literal.");
+                } else {
+                    item_count += 1;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit(Instruction::BuildList(item_count));
+    }
+
+    fn map(&mut self) {
+        let mut entry_count: u8 = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == u8::MAX {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                } else {
+                    entry_count += 1;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit(Instruction::BuildMap(entry_count));
+    }
+
     fn literal(&mut self) {
         use TokenType::*;
         match self.previous.token_type {
-            False => self.emit_byte(OP_FALSE as u8),
-            Nil => self.emit_byte(OP_NIL as u8),
-            True => self.emit_byte(OP_TRUE as u8),
+            False => self.emit(Instruction::False),
+            Nil => self.emit(Instruction::Nil),
+            True => self.emit(Instruction::True),
             _ => unreachable!(),
         }
     }
@@ -243,22 +1041,131 @@ impl Compiler {
 
     fn number(&mut self) {
         let value = self.previous.lexeme.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(value));
+        self.emit_constant(Value::number(value));
     }
 
     fn string(&mut self) {
-        let value = self.previous.lexeme[1..self.previous.lexeme.len() - 1].to_string();
-        self.emit_constant(Value::Object(Object::String(value)));
+        // The scanner already rejected malformed escapes.
+        let chars = decode_string(self.previous.lexeme).unwrap_or_default();
+        let string = self.intern(&chars);
+        self.emit_constant(Value::object(string));
+    }
+
+    /// Compiles `"a ${x} b ${y} c"` as the parts `"a "`, `x`, `" b "`, `y`
+    /// and `" c"`, which `BuildString` then formats and joins.
+    fn interpolation(&mut self) {
+        // A part that resumes after `}` can only follow an embedded expression.
+        if self.previous.lexeme.starts_with('}') {
+            self.error("Expect expression.");
+            return;
+        }
+
+        let mut part_count: usize = 0;
+        loop {
+            self.string();
+            self.expression();
+            part_count += 2;
+            if !self.match_token(TokenType::Interpolation) {
+                break;
+            }
+        }
+        if !self.match_token(TokenType::InterpolationEnd) {
+            self.error_at_current("Expect end of string after interpolation.");
+            return;
+        }
+        self.string();
+        part_count += 1;
+
+        if part_count > u8::MAX as usize {
+            self.error("Too many parts in string interpolation.");
+            return;
+        }
+        self.emit(Instruction::BuildString(part_count as u8));
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        // `name` may be synthetic, so attribute the access to the token
+        // just consumed instead.
+        let span = self.previous.span;
+        let state = self.states.len() - 1;
+        let (get_op, set_op) = if let Some(arg) = self.resolve_local(state, name) {
+            (Instruction::GetLocal(arg), Instruction::SetLocal(arg))
+        } else if let Some(arg) = self.resolve_upvalue(state, name) {
+            (Instruction::GetUpvalue(arg), Instruction::SetUpvalue(arg))
+        } else {
+            let arg = self.identifier_constant(name);
+            (
+                constant_op(arg, Instruction::GetGlobal, Instruction::GetGlobalLong),
+                constant_op(arg, Instruction::SetGlobal, Instruction::SetGlobalLong),
+            )
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_at(set_op, span);
+        } else {
+            self.emit_at(get_op, span);
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous;
+        self.named_variable(&name, can_assign);
+    }
+
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.previous;
+        let span = name.span;
+        let name = self.identifier_constant(&name);
+
+        self.named_variable(&Token::synthetic("this"), false);
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(&Token::synthetic("super"), false);
+            let op = constant_op(
+                name,
+                |name| Instruction::SuperInvoke { name, arg_count },
+                |name| Instruction::SuperInvokeLong { name, arg_count },
+            );
+            self.emit_at(op, span);
+        } else {
+            self.named_variable(&Token::synthetic("super"), false);
+            self.emit(constant_op(
+                name,
+                Instruction::GetSuper,
+                Instruction::GetSuperLong,
+            ));
+        }
+    }
+
+    fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
     }
 
     fn unary(&mut self) {
         let operator_type = self.previous.token_type;
+        let span = self.previous.span;
 
         self.parse_precedence(Precedence::Unary);
 
         match operator_type {
-            TokenType::Bang => self.emit_byte(OP_NOT as u8),
-            TokenType::Minus => self.emit_byte(OP_NEGATE as u8),
+            TokenType::Bang => self.emit_at(Instruction::Not, span),
+            TokenType::Minus => self.emit_at(Instruction::Negate, span),
             _ => unreachable!(),
         }
     }
@@ -266,12 +1173,15 @@ impl Compiler {
     fn get_rule(&mut self, t: TokenType) -> ParseRule {
         use TokenType::*;
         match t {
-            LeftParen => rule!(grouping, None, None),
+            LeftParen => rule!(grouping, call, Call),
             RightParen => rule!(None, None, None),
-            LeftBrace => rule!(None, None, None),
+            LeftBrace => rule!(map, None, None),
             RightBrace => rule!(None, None, None),
+            LeftBracket => rule!(list, index, Call),
+            RightBracket => rule!(None, None, None),
+            Colon => rule!(None, None, None),
             Comma => rule!(None, None, None),
-            Dot => rule!(None, None, None),
+            Dot => rule!(None, dot, Call),
             Minus => rule!(unary, binary, Term),
             Plus => rule!(None, binary, Term),
             Semicolon => rule!(None, None, None),
@@ -285,10 +1195,12 @@ impl Compiler {
             GreaterEqual => rule!(None, binary, Comparison),
             Less => rule!(None, binary, Comparison),
             LessEqual => rule!(None, binary, Comparison),
-            Identifier => rule!(None, None, None),
+            Identifier => rule!(variable, None, None),
             String => rule!(string, None, None),
+            Interpolation => rule!(interpolation, None, None),
+            InterpolationEnd => rule!(None, None, None),
             Number => rule!(number, None, None),
-            And => rule!(None, None, None),
+            And => rule!(None, and_, And),
             Class => rule!(None, None, None),
             Else => rule!(None, None, None),
             False => rule!(literal, None, None),
@@ -296,11 +1208,11 @@ impl Compiler {
             Fun => rule!(None, None, None),
             If => rule!(None, None, None),
             Nil => rule!(literal, None, None),
-            Or => rule!(None, None, None),
+            Or => rule!(None, or_, Or),
             Print => rule!(None, None, None),
             Return => rule!(None, None, None),
-            Super => rule!(None, None, None),
-            This => rule!(None, None, None),
+            Super => rule!(super_, None, None),
+            This => rule!(this_, None, None),
             True => rule!(literal, None, None),
             Var => rule!(None, None, None),
             While => rule!(None, None, None),
@@ -313,41 +1225,233 @@ impl Compiler {
 mod parse_fn {
     use super::*;
 
-    pub fn binary(compiler: &mut Compiler) {
+    pub fn binary(compiler: &mut Compiler, _can_assign: bool) {
         compiler.binary();
     }
 
-    pub fn grouping(compiler: &mut Compiler) {
+    pub fn call(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.call();
+    }
+
+    pub fn dot(compiler: &mut Compiler, can_assign: bool) {
+        compiler.dot(can_assign);
+    }
+
+    pub fn index(compiler: &mut Compiler, can_assign: bool) {
+        compiler.index(can_assign);
+    }
+
+    pub fn list(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.list();
+    }
+
+    pub fn map(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.map();
+    }
+
+    pub fn grouping(compiler: &mut Compiler, _can_assign: bool) {
         compiler.grouping();
     }
 
-    pub fn number(compiler: &mut Compiler) {
+    pub fn number(compiler: &mut Compiler, _can_assign: bool) {
         compiler.number();
     }
 
-    pub fn string(compiler: &mut Compiler) {
+    pub fn interpolation(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.interpolation();
+    }
+
+    pub fn string(compiler: &mut Compiler, _can_assign: bool) {
         compiler.string();
     }
 
-    pub fn unary(compiler: &mut Compiler) {
+    pub fn variable(compiler: &mut Compiler, can_assign: bool) {
+        compiler.variable(can_assign);
+    }
+
+    pub fn super_(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.super_();
+    }
+
+    pub fn this_(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.this();
+    }
+
+    pub fn unary(compiler: &mut Compiler, _can_assign: bool) {
         compiler.unary();
     }
 
-    pub fn literal(compiler: &mut Compiler) {
+    pub fn literal(compiler: &mut Compiler, _can_assign: bool) {
         compiler.literal();
     }
+
+    pub fn and_(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.and();
+    }
+
+    pub fn or_(compiler: &mut Compiler, _can_assign: bool) {
+        compiler.or();
+    }
 }
 
-pub fn compile(source: String) -> Option<Chunk> {
-    let mut compiler = Compiler::new(Scanner::new(source), Chunk::new());
+/// Compiles `source` into a script function, or returns every error found.
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+    vm_roots: &dyn Fn(&mut Heap),
+    print_code: bool,
+) -> Result<ObjRef, Vec<Diagnostic>> {
+    let mut compiler = Compiler::new(Scanner::new(source), heap, vm_roots);
+    compiler.print_code = print_code;
     compiler.advance();
-    compiler.expression();
-    compiler.consume(TokenType::Eof, "Expect end of expression.");
-    compiler.end_compiler();
+
+    while !compiler.match_token(TokenType::Eof) {
+        compiler.declaration();
+    }
+
+    let state = compiler.end_compiler();
+    let function = compiler.alloc(Object::Function(state.function));
 
     if !compiler.had_error.get() {
-        Some(compiler.current_chunk)
+        Ok(function)
     } else {
-        None
+        Err(compiler.diagnostics.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str) -> Vec<(usize, String)> {
+        let mut heap = Heap::new();
+        match compile(source, &mut heap, &|_| {}, false) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics
+                .into_iter()
+                .map(|diagnostic| (diagnostic.span.line, diagnostic.message))
+                .collect(),
+        }
+    }
+
+    fn expect(errors: Vec<(usize, String)>, expected: &[(usize, &str)]) {
+        let errors: Vec<(usize, &str)> = errors
+            .iter()
+            .map(|(line, message)| (*line, message.as_str()))
+            .collect();
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn reports_every_independent_error() {
+        let source = "\
+var a = ;
+print a
+var b = 1;
+fun f( { return 1; }
+print b;
+class C < C {}
+if (b) print 1 +;
+";
+        expect(
+            errors(source),
+            &[
+                (1, "Expect expression."),
+                (3, "Expect ';' after value."),
+                (4, "Expect parameter name."),
+                (6, "A class can't inherit from itself."),
+                (7, "Expect expression."),
+            ],
+        );
+    }
+
+    #[test]
+    fn recovers_inside_nested_blocks() {
+        let source = "\
+fun outer() {
+  var x = 1 +;
+  {
+    return return;
+  }
+  x = ;
+}
+print \"after\" \"\\q\";
+";
+        expect(
+            errors(source),
+            &[
+                (2, "Expect expression."),
+                (4, "Expect expression."),
+                (6, "Expect expression."),
+                (8, "Invalid escape sequence."),
+            ],
+        );
+    }
+
+    #[test]
+    fn one_error_per_statement() {
+        // Everything up to the next `;` is skipped after the first error.
+        expect(
+            errors("print 1 + + 2 ) (;\nprint 3;"),
+            &[(1, "Expect expression.")],
+        );
+    }
+
+    #[test]
+    fn valid_code_has_no_errors() {
+        expect(errors("var a = 1;\nprint a;"), &[]);
+    }
+
+    #[test]
+    fn identical_constants_share_a_slot() {
+        let mut heap = Heap::new();
+        let function = compile(
+            "print 1; print \"a\"; print 1; print \"a\"; print 2;",
+            &mut heap,
+            &|_| {},
+            false,
+        )
+        .unwrap();
+        assert_eq!(heap.as_function(function).chunk.constants().len(), 3);
+
+        let zero = ConstantKey::of(Value::number(0.0));
+        assert_ne!(zero, ConstantKey::of(Value::number(-0.0)));
+    }
+
+    #[test]
+    fn names_past_the_first_256_constants_use_long_forms() {
+        let mut source: String = (0..300).map(|i| format!("{};\n", i)).collect();
+        source.push_str(
+            "fun f() { return 1; }
+class A { m() { return \"a\"; } }
+class B < A { m() { return super.m(); } }
+var b = B();
+b.x = f();
+var y = b.x + 1;
+y = y + 1;
+",
+        );
+
+        let mut heap = Heap::new();
+        let function = compile(&source, &mut heap, &|_| {}, false).unwrap();
+        let opcodes: Vec<String> = heap
+            .as_function(function)
+            .chunk
+            .instructions()
+            .map(|decoded| format!("{:?}", decoded.unwrap().1.opcode()))
+            .collect();
+        for opcode in [
+            "OP_CONSTANT_LONG",
+            "OP_CLOSURE_LONG",
+            "OP_CLASS_LONG",
+            "OP_METHOD_LONG",
+            "OP_DEFINE_GLOBAL_LONG",
+            "OP_GET_GLOBAL_LONG",
+            "OP_SET_GLOBAL_LONG",
+            "OP_GET_PROPERTY_LONG",
+            "OP_SET_PROPERTY_LONG",
+        ] {
+            assert!(opcodes.iter().any(|op| op == opcode), "no {}", opcode);
+        }
     }
 }
@@ -0,0 +1,295 @@
+use std::{fmt::Display, rc::Rc};
+
+use crate::{
+    memory::ObjRef,
+    object::{NativeFn, Object},
+    value::Value,
+    vm::Vm,
+};
+
+/// A Rust type that can be read from a Lox value, such as a native
+/// function's parameter.
+pub trait FromLox: Sized {
+    /// Converts `value`, or returns `None` if it has the wrong type.
+    fn from_lox(vm: &Vm, value: Value) -> Option<Self>;
+
+    /// What the type is called in error messages, such as "a number".
+    fn expected() -> String;
+}
+
+/// A Rust type that can be turned into a Lox value, such as a native
+/// function's result.
+pub trait IntoLox {
+    fn into_lox(self, vm: &mut Vm) -> Value;
+}
+
+/// An object value is only kept alive for the duration of the call. A native
+/// that stores one and returns it after the object was collected fails with
+/// a runtime error instead of handing the script whatever reused its slot.
+impl FromLox for Value {
+    fn from_lox(_vm: &Vm, value: Value) -> Option<Value> {
+        Some(value)
+    }
+
+    fn expected() -> String {
+        "any value".to_owned()
+    }
+}
+
+impl IntoLox for Value {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        self
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(_vm: &Vm, value: Value) -> Option<f64> {
+        value.as_number()
+    }
+
+    fn expected() -> String {
+        "a number".to_owned()
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::number(self)
+    }
+}
+
+impl FromLox for bool {
+    fn from_lox(_vm: &Vm, value: Value) -> Option<bool> {
+        value.as_bool()
+    }
+
+    fn expected() -> String {
+        "a boolean".to_owned()
+    }
+}
+
+impl IntoLox for bool {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::boolean(self)
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(vm: &Vm, value: Value) -> Option<String> {
+        string_ref(vm, value).map(|string| vm.heap().as_string(string).chars.clone())
+    }
+
+    fn expected() -> String {
+        "a string".to_owned()
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        Value::object(vm.take_string(self))
+    }
+}
+
+/// `nil` maps to `None`.
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(vm: &Vm, value: Value) -> Option<Option<T>> {
+        if value.is_nil() {
+            Some(None)
+        } else {
+            T::from_lox(vm, value).map(Some)
+        }
+    }
+
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+}
+
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        match self {
+            Some(value) => value.into_lox(vm),
+            None => Value::nil(),
+        }
+    }
+}
+
+/// Natives that return nothing return `nil`.
+impl IntoLox for () {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::nil()
+    }
+}
+
+fn string_ref(vm: &Vm, value: Value) -> Option<ObjRef> {
+    let is_string =
+        value.is_object() && matches!(vm.heap().get(value.as_object()), Object::String(_));
+    is_string.then(|| value.as_object())
+}
+
+/// What a typed native may return: a value, or a `Result` whose error
+/// becomes a runtime error.
+pub trait NativeResult {
+    fn into_result(self, vm: &mut Vm) -> Result<Value, String>;
+}
+
+impl<T: IntoLox> NativeResult for T {
+    fn into_result(self, vm: &mut Vm) -> Result<Value, String> {
+        Ok(self.into_lox(vm))
+    }
+}
+
+impl<T: IntoLox, E: Display> NativeResult for Result<T, E> {
+    fn into_result(self, vm: &mut Vm) -> Result<Value, String> {
+        match self {
+            Ok(value) => Ok(value.into_lox(vm)),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+/// A Rust closure usable as a native function, taking up to eight
+/// `FromLox` parameters. `Args` is the tuple of parameter types.
+pub trait IntoNative<Args> {
+    fn arity(&self) -> usize;
+
+    /// Wraps the closure to check and convert its arguments. `name` is
+    /// used in error messages.
+    fn into_native(self, name: String) -> NativeFn;
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident $param:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: NativeResult,
+            $($arg: FromLox,)*
+        {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($arg)),*])
+            }
+
+            #[allow(unused_variables, unused_mut)]
+            fn into_native(self, name: String) -> NativeFn {
+                Rc::new(move |vm: &mut Vm, args: &[Value]| {
+                    let mut args = args.iter().enumerate();
+                    $(
+                        let (i, &value) = args.next().unwrap();
+                        let $param = $arg::from_lox(vm, value).ok_or_else(|| {
+                            format!(
+                                "{}() expects {} for argument {}.",
+                                name,
+                                $arg::expected(),
+                                i + 1
+                            )
+                        })?;
+                    )*
+                    self($($param),*).into_result(vm)
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A a);
+impl_into_native!(A a, B b);
+impl_into_native!(A a, B b, C c);
+impl_into_native!(A a, B b, C c, D d);
+impl_into_native!(A a, B b, C c, D d, E e);
+impl_into_native!(A a, B b, C c, D d, E e, G g);
+impl_into_native!(A a, B b, C c, D d, E e, G g, H h);
+impl_into_native!(A a, B b, C c, D d, E e, G g, H h, I i);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{LoxError, RuntimeError, RuntimeErrorKind, Value, Vm};
+
+    fn runtime_error(vm: &mut Vm, source: &str) -> RuntimeError {
+        match vm.interpret(source) {
+            Err(LoxError::Runtime(error)) => error,
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn converts_arguments_and_results() {
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::new();
+        let log = seen.clone();
+        vm.define_function("describe", move |name: String, n: f64, flag: bool| {
+            log.borrow_mut().push(format!("{} {} {}", name, n, flag));
+            format!("{}!", name)
+        });
+        let log = seen.clone();
+        vm.define_function("maybe", move |name: Option<String>| {
+            log.borrow_mut().push(format!("{:?}", name));
+        });
+        let log = seen.clone();
+        vm.define_function("record", move |n: f64| log.borrow_mut().push(n.to_string()));
+        vm.define_function("add", |a: f64, b: f64| a + b);
+
+        vm.interpret("var s = describe(\"hi\", 2, true);\nmaybe(s); maybe(nil);")
+            .unwrap();
+        vm.interpret("record(add(1, 2));").unwrap();
+        assert_eq!(
+            *seen.borrow(),
+            ["hi 2 true", "Some(\"hi!\")", "None", "3"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn checks_argument_types_and_count() {
+        let mut vm = Vm::new();
+        vm.define_function("add", |a: f64, b: f64| a + b);
+
+        let error = runtime_error(&mut vm, "1;\nadd(1, \"two\");");
+        assert_eq!(error.kind, RuntimeErrorKind::Native);
+        assert_eq!(error.message, "add() expects a number for argument 2.");
+        assert_eq!(error.line(), 2);
+
+        let error = runtime_error(&mut vm, "add(1);");
+        assert_eq!(error.kind, RuntimeErrorKind::Arity);
+    }
+
+    #[test]
+    fn stored_values_are_checked_when_returned() {
+        let stash = Rc::new(RefCell::new(Value::nil()));
+        let mut vm = Vm::builder().gc_stress(true).build();
+        let slot = stash.clone();
+        vm.define_function("stash", move |value: Value| *slot.borrow_mut() = value);
+        let slot = stash.clone();
+        vm.define_function("unstash", move || *slot.borrow());
+
+        vm.interpret("var kept = [1]; stash(kept);").unwrap();
+        vm.interpret("if (unstash() != kept) nil();").unwrap();
+
+        vm.interpret("stash([2]); var x = \"x\";").unwrap();
+        let error = runtime_error(&mut vm, "print unstash();");
+        assert_eq!(error.kind, RuntimeErrorKind::Native);
+        assert_eq!(
+            error.message,
+            "unstash() returned an invalid value: Handle refers to an object that was collected."
+        );
+    }
+
+    #[test]
+    fn native_errors_become_runtime_errors() {
+        let mut vm = Vm::new();
+        vm.define_function("sqrt", |n: f64| {
+            if n < 0.0 {
+                Err(format!("Can't take the square root of {}.", n))
+            } else {
+                Ok(n.sqrt())
+            }
+        });
+
+        let error = runtime_error(&mut vm, "\n\nsqrt(-4);");
+        assert_eq!(error.kind, RuntimeErrorKind::Native);
+        assert_eq!(error.message, "Can't take the square root of -4.");
+        assert_eq!(error.line(), 3);
+    }
+}
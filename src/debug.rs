@@ -1,60 +1,123 @@
 use crate::{
-    chunk::{
-        Chunk,
-        OpCode::{self, *},
-    },
+    chunk::{Chunk, Instruction},
+    memory::Heap,
     value::print_value,
 };
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
+pub fn disassemble_chunk(heap: &Heap, chunk: &Chunk, name: &str) {
     println!("== {name} ==");
 
-    let mut offset = 0;
-    while offset < chunk.code.len() {
-        offset = disassemble_instruction(&chunk, offset);
+    for decoded in chunk.instructions() {
+        match decoded {
+            Ok((offset, instruction)) => print_instruction(heap, chunk, offset, instruction),
+            Err(error) => println!("{}", error),
+        }
     }
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
+pub fn disassemble_instruction(heap: &Heap, chunk: &Chunk, offset: usize) -> usize {
+    match chunk.decode(offset) {
+        Ok(instruction) => {
+            print_instruction(heap, chunk, offset, instruction);
+            offset + instruction.size()
+        }
+        Err(error) => {
+            println!("{}", error);
+            offset + 1
+        }
+    }
+}
+
+fn print_instruction(heap: &Heap, chunk: &Chunk, offset: usize, instruction: Instruction) {
     print!("{:04} ", offset);
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+    let line = chunk.get_line(offset);
+    if offset > 0 && line == chunk.get_line(offset - 1) {
         print!("   | ")
     } else {
-        print!("{:4} ", chunk.lines[offset])
+        print!("{:4} ", line)
     }
 
-    let code: OpCode = chunk.code[offset].into();
-    match code {
-        OP_CONSTANT => constant_instruction("OP_CONSTANT", chunk, offset),
-        OP_NIL => simple_instruction("OP_NIL", offset),
-        OP_TRUE => simple_instruction("OP_TRUE", offset),
-        OP_FALSE => simple_instruction("OP_FALSE", offset),
-        OP_EQUAL => simple_instruction("OP_EQUAL", offset),
-        OP_GREATER => simple_instruction("OP_GREATER", offset),
-        OP_LESS => simple_instruction("OP_LESS", offset),
-        OP_ADD => simple_instruction("OP_ADD", offset),
-        OP_SUBTRACT => simple_instruction("OP_SUBTRACT", offset),
-        OP_MULTIPLY => simple_instruction("OP_MULTIPLY", offset),
-        OP_DIVIDE => simple_instruction("OP_DIVIDE", offset),
-        OP_NOT => simple_instruction("OP_NOT", offset),
-        OP_NEGATE => simple_instruction("OP_NEGATE", offset),
-        OP_RETURN => simple_instruction("OP_RETURN", offset),
-        unknown_opcode => {
-            println!("Unknown opcode {:?}", unknown_opcode);
-            offset + 1
+    let name = format!("{:?}", instruction.opcode());
+    let next = offset + instruction.size();
+    use Instruction::*;
+    match instruction {
+        Constant(constant)
+        | GetGlobal(constant)
+        | DefineGlobal(constant)
+        | SetGlobal(constant)
+        | GetProperty(constant)
+        | SetProperty(constant)
+        | GetSuper(constant)
+        | Class(constant)
+        | Method(constant) => constant_instruction(&name, heap, chunk, constant as usize),
+        ConstantLong(constant)
+        | GetGlobalLong(constant)
+        | DefineGlobalLong(constant)
+        | SetGlobalLong(constant)
+        | GetPropertyLong(constant)
+        | SetPropertyLong(constant)
+        | GetSuperLong(constant)
+        | ClassLong(constant)
+        | MethodLong(constant) => constant_instruction(&name, heap, chunk, constant as usize),
+        Jump(jump) | JumpIfFalse(jump) => jump_instruction(&name, offset, next + jump as usize),
+        Loop(jump) => jump_instruction(&name, offset, next - jump as usize),
+        Invoke {
+            name: constant,
+            arg_count,
+        }
+        | SuperInvoke {
+            name: constant,
+            arg_count,
+        } => invoke_instruction(&name, heap, chunk, constant as u32, arg_count),
+        InvokeLong {
+            name: constant,
+            arg_count,
+        }
+        | SuperInvokeLong {
+            name: constant,
+            arg_count,
+        } => invoke_instruction(&name, heap, chunk, constant, arg_count),
+        Closure(constant) => closure_instruction(&name, heap, chunk, constant as u32),
+        ClosureLong(constant) => closure_instruction(&name, heap, chunk, constant),
+        Call(operand)
+        | CaptureLocal(operand)
+        | CaptureUpvalue(operand)
+        | GetLocal(operand)
+        | SetLocal(operand)
+        | GetUpvalue(operand)
+        | SetUpvalue(operand)
+        | BuildList(operand)
+        | BuildMap(operand)
+        | BuildString(operand) => byte_instruction(&name, operand),
+        Nil | True | False | Equal | Greater | Less | Add | Subtract | Multiply | Divide | Not
+        | Negate | Print | CloseUpvalue | Pop | Return | Inherit | GetIndex | SetIndex => {
+            println!("{}", name)
         }
     }
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk.code[offset + 1];
+fn constant_instruction(name: &str, heap: &Heap, chunk: &Chunk, constant: usize) {
     print!("{:<16} {:>4} '", name, constant);
-    print_value(&chunk.constants.values[constant as usize]);
+    print_value(heap, &chunk.constants.values[constant]);
+    println!("'");
+}
+
+fn invoke_instruction(name: &str, heap: &Heap, chunk: &Chunk, constant: u32, arg_count: u8) {
+    print!("{:<16} ({} args) {:>4} '", name, arg_count, constant);
+    print_value(heap, &chunk.constants.values[constant as usize]);
     println!("'");
-    offset + 2
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    offset + 1
+fn closure_instruction(name: &str, heap: &Heap, chunk: &Chunk, constant: u32) {
+    print!("{:<16} {:>4} ", name, constant);
+    print_value(heap, &chunk.constants.values[constant as usize]);
+    println!();
+}
+
+fn byte_instruction(name: &str, operand: u8) {
+    println!("{:<16} {:>4}", name, operand);
+}
+
+fn jump_instruction(name: &str, offset: usize, target: usize) {
+    println!("{:<16} {:>4} -> {}", name, offset, target);
 }
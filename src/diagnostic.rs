@@ -0,0 +1,239 @@
+use std::fmt::Write;
+
+use derive_more::Display;
+
+use crate::scanner::Span;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    #[display(fmt = "error")]
+    Error,
+    #[display(fmt = "warning")]
+    Warning,
+}
+
+/// A problem found while compiling or running a script, pointing at the
+/// source it concerns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            notes: vec![],
+            help: None,
+        }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Diagnostic {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Formats the diagnostic for people: the message, its location, the
+    /// offending line of `source` with the span underlined, then any notes
+    /// and help. With `color` set the output uses ANSI escapes.
+    ///
+    /// The snippet is left out when the span does not lie within `source`,
+    /// as happens for code compiled from an earlier REPL line.
+    pub fn render(&self, path: &str, source: &str, color: bool) -> String {
+        let style = |code: &'static str| if color { code } else { "" };
+        let (bold, reset, gutter) = (style(BOLD), style(RESET), style(BLUE));
+        let severity = match self.severity {
+            Severity::Error => style(RED),
+            Severity::Warning => style(YELLOW),
+        };
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{severity}{}{reset}{bold}: {}{reset}",
+            self.severity, self.message
+        )
+        .unwrap();
+
+        let line_number = self.span.line.to_string();
+        let pad = " ".repeat(line_number.len());
+        writeln!(
+            out,
+            "{pad}{gutter}-->{reset} {}:{}:{}",
+            path, self.span.line, self.span.column
+        )
+        .unwrap();
+
+        if let Some(line) = source_line(source, self.span) {
+            // Reuse the line's own tabs so the caret lines up under them.
+            let indent: String = line
+                .chars()
+                .take(self.span.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let rest = line.chars().count() - (self.span.column - 1);
+            let width = source
+                .get(self.span.start..self.span.end)
+                .unwrap_or("")
+                .chars()
+                .take_while(|&c| c != '\n')
+                .count()
+                .clamp(1, rest.max(1));
+
+            writeln!(out, "{pad} {gutter}|{reset}").unwrap();
+            writeln!(out, "{gutter}{line_number} |{reset} {line}").unwrap();
+            writeln!(
+                out,
+                "{pad} {gutter}|{reset} {indent}{severity}{}{reset}",
+                "^".repeat(width)
+            )
+            .unwrap();
+        }
+
+        for note in &self.notes {
+            writeln!(out, "{pad} {gutter}={reset} {bold}note{reset}: {note}").unwrap();
+        }
+        if let Some(help) = &self.help {
+            writeln!(out, "{pad} {gutter}={reset} {bold}help{reset}: {help}").unwrap();
+        }
+        out
+    }
+
+    /// Formats the diagnostic as a single-line JSON object for tools.
+    pub fn to_json(&self, path: &str) -> String {
+        let mut out = String::new();
+        write!(
+            out,
+            "{{\"severity\":\"{}\",\"message\":{},\"file\":{},\
+             \"line\":{},\"column\":{},\"start\":{},\"end\":{},\"notes\":[",
+            self.severity,
+            json_string(&self.message),
+            json_string(path),
+            self.span.line,
+            self.span.column,
+            self.span.start,
+            self.span.end
+        )
+        .unwrap();
+        for (i, note) in self.notes.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(&json_string(note));
+        }
+        out.push_str("],\"help\":");
+        match &self.help {
+            Some(help) => out.push_str(&json_string(help)),
+            None => out.push_str("null"),
+        }
+        out.push('}');
+        out
+    }
+}
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// The line `span` starts on, if the span fits `source`.
+fn source_line(source: &str, span: Span) -> Option<&str> {
+    if span.start > source.len() || !source.is_char_boundary(span.start) {
+        return None;
+    }
+    let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |i| span.start + i);
+    let line = &source[line_start..line_end];
+
+    // Make sure the span really is this one, not a stale one from other source.
+    let column = source[line_start..span.start].chars().count() + 1;
+    let line_number = source[..line_start].matches('\n').count() + 1;
+    if column != span.column || line_number != span.line {
+        return None;
+    }
+    Some(line.strip_suffix('\r').unwrap_or(line))
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize, end: usize, line: usize, column: usize) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn renders_the_line_with_a_caret() {
+        let source = "var a = 1;\nprint a +\t\"é\" - nil;\n";
+        let diagnostic = Diagnostic::error("Operands must be numbers.", span(26, 27, 2, 15))
+            .with_note("test.lox:2:15 in script")
+            .with_help("convert the operand first");
+        assert_eq!(
+            diagnostic.render("test.lox", source, false),
+            "error: Operands must be numbers.\n \
+             --> test.lox:2:15\n  \
+             |\n\
+             2 | print a +\t\"é\" - nil;\n  \
+             |          \t    ^\n  \
+             = note: test.lox:2:15 in script\n  \
+             = help: convert the operand first\n"
+        );
+    }
+
+    #[test]
+    fn omits_the_snippet_for_spans_outside_the_source() {
+        let diagnostic = Diagnostic::error("Boom.", span(40, 41, 3, 2));
+        assert_eq!(
+            diagnostic.render("<repl>", "print 1;\n", false),
+            "error: Boom.\n --> <repl>:3:2\n"
+        );
+    }
+
+    #[test]
+    fn json_escapes_strings() {
+        let diagnostic = Diagnostic::error("Undefined key \"a\".", span(0, 3, 1, 1));
+        assert_eq!(
+            diagnostic.to_json("dir\\x.lox"),
+            "{\"severity\":\"error\",\"message\":\"Undefined key \\\"a\\\".\",\
+             \"file\":\"dir\\\\x.lox\",\"line\":1,\"column\":1,\"start\":0,\"end\":3,\
+             \"notes\":[],\"help\":null}"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use derive_more::Display;
+
+use crate::{diagnostic::Diagnostic, scanner::Span};
+
+/// What went wrong at runtime, for hosts that need to tell errors apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// An operand, receiver or callee had the wrong type.
+    Type,
+    UndefinedVariable,
+    UndefinedProperty,
+    UndefinedKey,
+    /// A value that can't be a map key was used as one.
+    InvalidKey,
+    /// A list index that isn't an integer or is out of bounds.
+    Index,
+    /// A call with the wrong number of arguments.
+    Arity,
+    StackOverflow,
+    /// A native function reported an error.
+    Native,
+    /// The bytecode could not be decoded.
+    InvalidBytecode,
+}
+
+/// One active call when a runtime error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// `None` for the top-level script.
+    pub function: Option<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Display, Clone, PartialEq, Eq)]
+#[display(fmt = "{}", message)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    /// Where the failing instruction came from.
+    pub span: Span,
+    /// The calls active at the time, innermost first.
+    pub stack_trace: Vec<StackFrame>,
+}
+
+impl RuntimeError {
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
+
+    /// Describes the error, with the calls that led to it as notes. The
+    /// innermost frame is where the diagnostic itself points, so the notes
+    /// start at its caller. Runs of identical frames, as left by deep
+    /// recursion, are shown once with a count.
+    pub fn to_diagnostic(&self, path: &str) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(self.message.clone(), self.span);
+        let callers = self.stack_trace.get(1..).unwrap_or_default();
+        for run in callers.chunk_by(|a, b| a == b) {
+            let frame = &run[0];
+            let location = format!("{}:{}:{}", path, frame.span.line, frame.span.column);
+            diagnostic = match &frame.function {
+                Some(name) => diagnostic.with_note(format!("{} in {}()", location, name)),
+                None => diagnostic.with_note(format!("{} in script", location)),
+            };
+            if run.len() > 1 {
+                let note = format!("... repeated {} more times", run.len() - 1);
+                diagnostic = diagnostic.with_note(note);
+            }
+        }
+        if self.kind == RuntimeErrorKind::StackOverflow {
+            diagnostic =
+                diagnostic.with_help("Check for recursion that never reaches a base case.");
+        }
+        diagnostic
+    }
+}
+
+/// Why `Vm::interpret` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoxError {
+    /// Every error the compiler found. Nothing was run.
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+    /// A `Script` or `Value` from a different `Vm` was passed in.
+    ForeignHandle,
+    /// An object `Value` was passed in after the object was collected.
+    /// Object values are only kept alive while the VM can reach them.
+    StaleHandle,
+}
+
+impl LoxError {
+    pub fn diagnostics(&self, path: &str) -> Vec<Diagnostic> {
+        match self {
+            LoxError::Compile(diagnostics) => diagnostics.clone(),
+            LoxError::Runtime(error) => vec![error.to_diagnostic(path)],
+            LoxError::ForeignHandle | LoxError::StaleHandle => {
+                vec![Diagnostic::error(self.to_string(), Span::default())]
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxError::Compile(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    let span = diagnostic.span;
+                    write!(f, "[{}:{}] {}", span.line, span.column, diagnostic.message)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(error) => {
+                write!(f, "[{}:{}] {}", error.line(), error.span.column, error)
+            }
+            LoxError::ForeignHandle => write!(f, "Handle belongs to a different Vm."),
+            LoxError::StaleHandle => write!(f, "Handle refers to an object that was collected."),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+impl std::error::Error for LoxError {}
@@ -0,0 +1,42 @@
+//! A bytecode interpreter for Lox.
+//!
+//! Create a [`Vm`], then hand it source code:
+//!
+//! ```
+//! let mut vm = rlox::Vm::new();
+//! vm.interpret("var greeting = \"hello\";").unwrap();
+//!
+//! let error = vm.interpret("print greeting - 1;").unwrap_err();
+//! assert!(matches!(
+//!     error,
+//!     rlox::LoxError::Runtime(rlox::RuntimeError {
+//!         kind: rlox::RuntimeErrorKind::Type,
+//!         ..
+//!     })
+//! ));
+//! ```
+//!
+//! Errors are plain values; [`Diagnostic::render`] formats them for people.
+
+mod chunk;
+mod compiler;
+mod convert;
+mod debug;
+mod diagnostic;
+mod error;
+mod memory;
+mod native;
+mod object;
+mod scanner;
+mod table;
+mod value;
+mod vm;
+
+pub use chunk::{Chunk, DecodeError, Instruction, Instructions};
+pub use convert::{FromLox, IntoLox, IntoNative, NativeResult};
+pub use diagnostic::{Diagnostic, Severity};
+pub use error::{LoxError, RuntimeError, RuntimeErrorKind, StackFrame};
+pub use object::NativeFn;
+pub use scanner::{decode_string, Span, StringError};
+pub use value::Value;
+pub use vm::{Script, Vm, VmBuilder};
@@ -1,47 +1,85 @@
-mod chunk;
-mod compiler;
-mod debug;
-mod object;
-mod scanner;
-mod value;
-mod vm;
-
-use crate::chunk::Chunk;
-use crate::vm::InterpretResult;
+use rlox::{LoxError, Vm};
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{self, BufRead};
-use vm::VM;
+use std::io::{self, BufRead, IsTerminal};
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let gc_stress = take_flag(&mut args, "--gc-stress");
+    let trace = take_flag(&mut args, "--trace");
+    let print_code = take_flag(&mut args, "--print-code");
+    let json = match args
+        .iter()
+        .position(|arg| arg.starts_with("--error-format="))
+    {
+        Some(position) => match args.remove(position).as_str() {
+            "--error-format=human" => false,
+            "--error-format=json" => true,
+            _ => usage(),
+        },
+        None => false,
+    };
 
+    let vm = Vm::builder()
+        .gc_stress(gc_stress)
+        .trace_execution(trace)
+        .print_code(print_code)
+        .build();
     match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => {
-            eprintln!("Usage: clox [path]");
-            std::process::exit(64);
+        0 => repl(vm, json),
+        1 => run_file(vm, &args[0], json),
+        _ => usage(),
+    }
+}
+
+/// Removes `flag` from `args`, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|arg| arg == flag) {
+        Some(position) => {
+            args.remove(position);
+            true
         }
+        None => false,
     }
 }
 
-fn repl() {
+fn usage() -> ! {
+    eprintln!(
+        "Usage: clox [--gc-stress] [--trace] [--print-code] [--error-format=human|json] [path]"
+    );
+    std::process::exit(64);
+}
+
+/// Prints `error` to stderr, either rendered against `source` or as one
+/// JSON object per line.
+fn report(error: &LoxError, path: &str, source: &str, json: bool) {
+    let color = io::stderr().is_terminal();
+    for diagnostic in error.diagnostics(path) {
+        if json {
+            eprintln!("{}", diagnostic.to_json(path));
+        } else {
+            eprint!("{}", diagnostic.render(path, source, color));
+        }
+    }
+}
+
+fn repl(mut vm: Vm, json: bool) {
     let stdin = io::stdin();
-    let mut vm = VM::new(Chunk::new());
 
     loop {
         print!("> ");
         io::stdout().flush().unwrap();
 
         let mut line = String::new();
-        stdin.lock().read_line(&mut line).unwrap();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            println!();
+            break;
+        }
 
-        let result = vm.interpret(line);
-        match result {
-            InterpretResult::CompileError => std::process::exit(65),
-            InterpretResult::RuntimeError => std::process::exit(70),
-            _ => (),
+        // Keep the session (and its globals) alive after errors.
+        if let Err(error) = vm.interpret(&line) {
+            report(&error, "<repl>", &line, json);
         }
     }
 }
@@ -53,13 +91,15 @@ fn read_file(path: &str) -> String {
     contents
 }
 
-fn run_file(path: &str) {
+fn run_file(mut vm: Vm, path: &str, json: bool) {
     let source = read_file(path);
-    let mut vm = VM::new(Chunk::new());
-    let result = vm.interpret(source);
-    match result {
-        InterpretResult::CompileError => std::process::exit(65),
-        InterpretResult::RuntimeError => std::process::exit(70),
-        _ => (),
+    if let Err(error) = vm.interpret(&source) {
+        report(&error, path, &source, json);
+        match error {
+            LoxError::Compile(_) => std::process::exit(65),
+            LoxError::Runtime(_) | LoxError::ForeignHandle | LoxError::StaleHandle => {
+                std::process::exit(70)
+            }
+        }
     }
 }
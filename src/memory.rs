@@ -0,0 +1,449 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    object::{
+        hash_string, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjMap, ObjNative,
+        ObjString, ObjUpvalue, Object,
+    },
+    table::{Key, Table},
+    value::Value,
+};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+/// Source of `Heap::id`s. Ids wrap around, so telling heaps apart is
+/// best-effort once more than 256 have been created.
+static NEXT_HEAP_ID: AtomicU8 = AtomicU8::new(0);
+
+/// A handle to an object living in the `Heap` with id `heap`. `generation`
+/// tells the object apart from later ones that reuse its slot; it wraps too,
+/// so spotting a stale handle is best-effort as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef {
+    index: u32,
+    heap: u8,
+    generation: u8,
+}
+
+impl ObjRef {
+    fn index(self) -> usize {
+        self.index as usize
+    }
+
+    /// Packs the handle into the low 48 bits.
+    #[cfg(feature = "nan-boxing")]
+    pub const fn to_raw(self) -> u64 {
+        ((self.generation as u64) << 40) | ((self.heap as u64) << 32) | self.index as u64
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub const fn from_raw(raw: u64) -> ObjRef {
+        ObjRef {
+            index: raw as u32,
+            heap: (raw >> 32) as u8,
+            generation: (raw >> 40) as u8,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HeapEntry {
+    object: Object,
+    /// Bytes accounted for this object when it was allocated or last
+    /// remeasured.
+    size: usize,
+}
+
+/// Owns every heap object and reclaims them with a tracing mark-sweep
+/// collector.
+#[derive(Debug)]
+pub struct Heap {
+    id: u8,
+    entries: Vec<Option<HeapEntry>>,
+    marks: Vec<bool>,
+    /// Generation of the object in each slot, or of the next one if the
+    /// slot is free.
+    generations: Vec<u8>,
+    free_slots: Vec<usize>,
+    gray_stack: Vec<ObjRef>,
+    /// Intern pool. Entries are weak: strings that are otherwise unreachable
+    /// are dropped from it during collection.
+    strings: Table,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Collect on every allocation instead of waiting for the threshold.
+    pub stress: bool,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            id: NEXT_HEAP_ID.fetch_add(1, Ordering::Relaxed),
+            entries: vec![],
+            marks: vec![],
+            generations: vec![],
+            free_slots: vec![],
+            gray_stack: vec![],
+            strings: Table::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress: false,
+        }
+    }
+
+    /// Moves `object` into the heap. If a collection is due it runs first,
+    /// with `mark_roots` marking everything the caller keeps alive. Objects
+    /// referenced by `object` itself are kept alive too.
+    pub fn alloc(&mut self, object: Object, mark_roots: &dyn Fn(&mut Heap)) -> ObjRef {
+        let size = object.size();
+        if self.stress || self.bytes_allocated + size > self.next_gc {
+            mark_roots(self);
+            self.mark_children(&object);
+            self.collect_garbage();
+        }
+        self.bytes_allocated += size;
+
+        let entry = Some(HeapEntry { object, size });
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.entries[slot] = entry;
+                self.marks[slot] = false;
+                slot
+            }
+            None => {
+                self.entries.push(entry);
+                self.marks.push(false);
+                self.generations.push(0);
+                self.entries.len() - 1
+            }
+        };
+        ObjRef {
+            index: slot as u32,
+            heap: self.id,
+            generation: self.generations[slot],
+        }
+    }
+
+    /// Updates the bytes accounted for `object` after it grew or shrank in
+    /// place, such as a list that was appended to. Growing can make a
+    /// collection due, so `object` must be reachable from `mark_roots`.
+    pub fn remeasure(&mut self, object: ObjRef, mark_roots: &dyn Fn(&mut Heap)) {
+        let entry = self.entries[object.index()].as_mut().unwrap();
+        let size = entry.object.size();
+        self.bytes_allocated = self.bytes_allocated - entry.size + size;
+        entry.size = size;
+
+        if self.stress || self.bytes_allocated > self.next_gc {
+            mark_roots(self);
+            self.collect_garbage();
+        }
+    }
+
+    /// Whether `object` was allocated by this heap rather than another one.
+    pub fn owns(&self, object: ObjRef) -> bool {
+        object.heap == self.id
+    }
+
+    /// Whether `object`, which this heap owns, has not been collected.
+    pub fn is_live(&self, object: ObjRef) -> bool {
+        self.entries[object.index()].is_some()
+            && self.generations[object.index()] == object.generation
+    }
+
+    /// Returns the interned string with these contents, allocating it if needed.
+    pub fn intern(&mut self, chars: &str, mark_roots: &dyn Fn(&mut Heap)) -> ObjRef {
+        let hash = hash_string(chars);
+        match self.strings.find_string(self, chars, hash) {
+            Some(interned) => interned,
+            None => self.alloc_string(chars.to_owned(), mark_roots),
+        }
+    }
+
+    /// Like `intern`, but takes ownership of an already built `String`.
+    pub fn take_string(&mut self, chars: String, mark_roots: &dyn Fn(&mut Heap)) -> ObjRef {
+        let hash = hash_string(&chars);
+        match self.strings.find_string(self, &chars, hash) {
+            Some(interned) => interned,
+            None => self.alloc_string(chars, mark_roots),
+        }
+    }
+
+    fn alloc_string(&mut self, chars: String, mark_roots: &dyn Fn(&mut Heap)) -> ObjRef {
+        let string = ObjString::new(chars);
+        let hash = string.hash;
+        let string = self.alloc(Object::String(string), mark_roots);
+        self.strings.set(Key::string(string, hash), Value::nil());
+        string
+    }
+
+    /// The table key for an interned string.
+    pub fn key(&self, string: ObjRef) -> Key {
+        Key::string(string, self.as_string(string).hash)
+    }
+
+    pub fn get(&self, object: ObjRef) -> &Object {
+        debug_assert!(self.owns(object) && self.is_live(object));
+        &self.entries[object.index()].as_ref().unwrap().object
+    }
+
+    pub fn get_mut(&mut self, object: ObjRef) -> &mut Object {
+        debug_assert!(self.owns(object) && self.is_live(object));
+        &mut self.entries[object.index()].as_mut().unwrap().object
+    }
+
+    pub fn as_string(&self, object: ObjRef) -> &ObjString {
+        match self.get(object) {
+            Object::String(s) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_function(&self, object: ObjRef) -> &ObjFunction {
+        match self.get(object) {
+            Object::Function(f) => f,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_closure(&self, object: ObjRef) -> &ObjClosure {
+        match self.get(object) {
+            Object::Closure(c) => c,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_upvalue(&self, object: ObjRef) -> &ObjUpvalue {
+        match self.get(object) {
+            Object::Upvalue(u) => u,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, object: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(object) {
+            Object::Upvalue(u) => u,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_class(&self, object: ObjRef) -> &ObjClass {
+        match self.get(object) {
+            Object::Class(c) => c,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_class_mut(&mut self, object: ObjRef) -> &mut ObjClass {
+        match self.get_mut(object) {
+            Object::Class(c) => c,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_instance(&self, object: ObjRef) -> &ObjInstance {
+        match self.get(object) {
+            Object::Instance(i) => i,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_instance_mut(&mut self, object: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(object) {
+            Object::Instance(i) => i,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_native(&self, object: ObjRef) -> &ObjNative {
+        match self.get(object) {
+            Object::Native(n) => n,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_list(&self, object: ObjRef) -> &ObjList {
+        match self.get(object) {
+            Object::List(l) => l,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_list_mut(&mut self, object: ObjRef) -> &mut ObjList {
+        match self.get_mut(object) {
+            Object::List(l) => l,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_map(&self, object: ObjRef) -> &ObjMap {
+        match self.get(object) {
+            Object::Map(m) => m,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_map_mut(&mut self, object: ObjRef) -> &mut ObjMap {
+        match self.get_mut(object) {
+            Object::Map(m) => m,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if value.is_object() {
+            self.mark_object(value.as_object());
+        }
+    }
+
+    pub fn mark_object(&mut self, object: ObjRef) {
+        if self.marks[object.index()] {
+            return;
+        }
+
+        self.marks[object.index()] = true;
+        self.gray_stack.push(object);
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_value(key.value());
+            self.mark_value(value);
+        }
+    }
+
+    fn mark_children(&mut self, object: &Object) {
+        match object {
+            Object::String(_) => {}
+            Object::Function(f) => {
+                for &constant in &f.chunk.constants.values {
+                    self.mark_value(constant);
+                }
+            }
+            Object::Closure(c) => {
+                self.mark_object(c.function);
+                for &upvalue in &c.upvalues {
+                    self.mark_object(upvalue);
+                }
+            }
+            Object::Upvalue(u) => {
+                if let Some(closed) = u.closed {
+                    self.mark_value(closed);
+                }
+            }
+            Object::Class(c) => self.mark_table(&c.methods),
+            Object::Instance(i) => {
+                self.mark_object(i.class);
+                self.mark_table(&i.fields);
+            }
+            Object::BoundMethod(b) => {
+                self.mark_value(b.receiver);
+                self.mark_object(b.method);
+            }
+            Object::Native(_) => {}
+            Object::List(l) => {
+                for &item in &l.items {
+                    self.mark_value(item);
+                }
+            }
+            Object::Map(m) => self.mark_table(&m.entries),
+        }
+    }
+
+    fn blacken_object(&mut self, object: ObjRef) {
+        // Take the entry out while its children are marked so the heap can be
+        // borrowed mutably; it is put back untouched.
+        let entry = self.entries[object.index()].take().unwrap();
+        self.mark_children(&entry.object);
+        self.entries[object.index()] = Some(entry);
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(object) = self.gray_stack.pop() {
+            self.blacken_object(object);
+        }
+    }
+
+    fn remove_white_strings(&mut self) {
+        let marks = &self.marks;
+        self.strings.retain(|key| marks[key.as_string().index()]);
+    }
+
+    fn sweep(&mut self) {
+        for (slot, entry) in self.entries.iter_mut().enumerate() {
+            if self.marks[slot] {
+                self.marks[slot] = false;
+            } else if let Some(freed) = entry.take() {
+                self.bytes_allocated -= freed.size;
+                self.generations[slot] = self.generations[slot].wrapping_add(1);
+                self.free_slots.push(slot);
+            }
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        self.trace_references();
+        self.remove_white_strings();
+        self.sweep();
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::ObjList;
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let mut heap = Heap::new();
+        heap.stress = true;
+
+        let garbage = heap.intern("garbage", &|_| {});
+        let item = heap.intern("item", &|_| {});
+        // Nothing kept `garbage` alive, so its slot was reused, and the old
+        // handle can tell.
+        assert_eq!(item.index, garbage.index);
+        assert!(heap.is_live(item) && !heap.is_live(garbage));
+        assert_eq!(heap.bytes_allocated, heap.get(item).size());
+
+        // The object being allocated keeps what it references alive.
+        let list = heap.alloc(
+            Object::List(ObjList::new(vec![Value::object(item)])),
+            &|_| {},
+        );
+        let roots = |heap: &mut Heap| heap.mark_object(list);
+        let other = heap.intern("other", &roots);
+        assert!(![list, item].contains(&other));
+        assert_eq!(heap.as_string(item).chars, "item");
+
+        // Only `other` was unreachable; the root and its item survive.
+        let last = heap.intern("last", &roots);
+        assert_eq!(last.index, other.index);
+        assert!(!heap.is_live(other));
+        assert_eq!(heap.as_list(list).items.len(), 1);
+        assert_eq!(heap.as_string(item).chars, "item");
+    }
+
+    #[test]
+    fn growth_in_place_counts_towards_collection() {
+        let mut heap = Heap::new();
+        let list = heap.alloc(Object::List(ObjList::new(vec![])), &|_| {});
+        let roots = |heap: &mut Heap| heap.mark_object(list);
+        let garbage = heap.intern("garbage", &roots);
+
+        heap.as_list_mut(list).items.push(Value::nil());
+        heap.remeasure(list, &roots);
+        assert!(heap.is_live(garbage));
+        let size = heap.get(list).size() + heap.get(garbage).size();
+        assert_eq!(heap.bytes_allocated, size);
+
+        // Growing past the threshold collects, just like allocating.
+        let items = GC_INITIAL_THRESHOLD / std::mem::size_of::<Value>();
+        heap.as_list_mut(list).items.resize(items, Value::nil());
+        heap.remeasure(list, &roots);
+        assert!(!heap.is_live(garbage));
+        assert_eq!(heap.bytes_allocated, heap.get(list).size());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{
+    memory::ObjRef,
+    object::{ObjList, Object},
+    table::Key,
+    value::Value,
+    vm::Vm,
+};
+
+/// Registers the built-in natives in `vm`'s globals.
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("len", 1, len);
+    vm.define_native("append", 2, append);
+    vm.define_native("insert", 3, insert);
+    vm.define_native("remove", 2, remove);
+    vm.define_native("slice", 3, slice);
+    vm.define_native("has", 2, has);
+    vm.define_native("delete", 2, delete);
+    vm.define_native("keys", 1, keys);
+    vm.define_native("values", 1, values);
+    vm.define_native("entries", 1, entries);
+}
+
+fn list_arg(vm: &Vm, value: Value, function: &str) -> Result<ObjRef, String> {
+    if value.is_object() && matches!(vm.heap().get(value.as_object()), Object::List(_)) {
+        Ok(value.as_object())
+    } else {
+        Err(format!("{}() expects a list.", function))
+    }
+}
+
+fn map_arg(vm: &Vm, value: Value, function: &str) -> Result<ObjRef, String> {
+    if value.is_object() && matches!(vm.heap().get(value.as_object()), Object::Map(_)) {
+        Ok(value.as_object())
+    } else {
+        Err(format!("{}() expects a map.", function))
+    }
+}
+
+fn key_arg(vm: &Vm, value: Value) -> Result<Key, String> {
+    Key::from_value(vm.heap(), value).map_err(str::to_owned)
+}
+
+/// Converts `value` into an index into a list of length `len`. Positions are
+/// also allowed to point just past the last item.
+fn index_arg(value: Value, len: usize, is_position: bool) -> Result<usize, String> {
+    if !value.is_number() || value.as_number_unchecked().fract() != 0.0 {
+        return Err("List index must be an integer.".to_owned());
+    }
+
+    let index = value.as_number_unchecked();
+    let end = if is_position { len + 1 } else { len };
+    if index < 0.0 || index >= end as f64 {
+        return Err(format!(
+            "List index {} out of bounds for length {}.",
+            index, len
+        ));
+    }
+    Ok(index as usize)
+}
+
+fn len(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let value = args[0];
+    if value.is_object() {
+        match vm.heap().get(value.as_object()) {
+            Object::List(l) => return Ok(Value::number(l.items.len() as f64)),
+            Object::Map(m) => return Ok(Value::number(m.entries.len() as f64)),
+            Object::String(s) => return Ok(Value::number(s.chars.chars().count() as f64)),
+            _ => {}
+        }
+    }
+    Err("len() expects a list, map or string.".to_owned())
+}
+
+fn append(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let list = list_arg(vm, args[0], "append")?;
+    vm.heap_mut().as_list_mut(list).items.push(args[1]);
+    vm.remeasure(list);
+    Ok(Value::nil())
+}
+
+fn insert(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let list = list_arg(vm, args[0], "insert")?;
+    let items = &mut vm.heap_mut().as_list_mut(list).items;
+    let index = index_arg(args[1], items.len(), true)?;
+    items.insert(index, args[2]);
+    vm.remeasure(list);
+    Ok(Value::nil())
+}
+
+fn remove(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let list = list_arg(vm, args[0], "remove")?;
+    let items = &mut vm.heap_mut().as_list_mut(list).items;
+    let index = index_arg(args[1], items.len(), false)?;
+    Ok(items.remove(index))
+}
+
+/// Returns a new list with the items in `start..end`.
+fn slice(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let list = list_arg(vm, args[0], "slice")?;
+    let items = &vm.heap().as_list(list).items;
+    let start = index_arg(args[1], items.len(), true)?;
+    let end = index_arg(args[2], items.len(), true)?;
+    if start > end {
+        return Err("Slice start must not be after its end.".to_owned());
+    }
+
+    let items = items[start..end].to_vec();
+    let slice = vm.alloc(Object::List(ObjList::new(items)));
+    Ok(Value::object(slice))
+}
+
+fn has(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg(vm, args[0], "has")?;
+    let key = key_arg(vm, args[1])?;
+    let found = vm.heap().as_map(map).entries.get(key).is_some();
+    Ok(Value::boolean(found))
+}
+
+/// Removes `key`, returning whether it was present.
+fn delete(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg(vm, args[0], "delete")?;
+    let key = key_arg(vm, args[1])?;
+    let deleted = vm.heap_mut().as_map_mut(map).entries.delete(key);
+    Ok(Value::boolean(deleted))
+}
+
+fn keys(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg(vm, args[0], "keys")?;
+    let items = vm
+        .heap()
+        .as_map(map)
+        .entries
+        .iter()
+        .map(|(key, _)| key.value())
+        .collect();
+    let list = vm.alloc(Object::List(ObjList::new(items)));
+    Ok(Value::object(list))
+}
+
+fn values(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg(vm, args[0], "values")?;
+    let items = vm
+        .heap()
+        .as_map(map)
+        .entries
+        .iter()
+        .map(|(_, value)| value)
+        .collect();
+    let list = vm.alloc(Object::List(ObjList::new(items)));
+    Ok(Value::object(list))
+}
+
+/// Returns a list of `[key, value]` pairs, in the same order as `keys`.
+fn entries(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg(vm, args[0], "entries")?;
+    let pairs: Vec<(Value, Value)> = vm
+        .heap()
+        .as_map(map)
+        .entries
+        .iter()
+        .map(|(key, value)| (key.value(), value))
+        .collect();
+
+    // The result list is rooted on the stack while each pair is allocated.
+    let list = vm.alloc(Object::List(ObjList::new(Vec::with_capacity(pairs.len()))));
+    vm.push(Value::object(list));
+    for (key, value) in pairs {
+        let pair = vm.alloc(Object::List(ObjList::new(vec![key, value])));
+        vm.heap_mut()
+            .as_list_mut(list)
+            .items
+            .push(Value::object(pair));
+    }
+    vm.pop();
+    Ok(Value::object(list))
+}
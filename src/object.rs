@@ -1,10 +1,280 @@
-#[derive(Debug, Clone)]
+use std::{fmt::Write, rc::Rc};
+
+use crate::{
+    chunk::{Chunk, LineStart},
+    memory::{Heap, ObjRef},
+    table::{Key, Table},
+    value::{write_value, Value},
+    vm::Vm,
+};
+
+#[derive(Debug)]
 pub enum Object {
-    String(String),
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+    List(ObjList),
+    Map(ObjMap),
+}
+
+const TABLE_ENTRY_SIZE: usize = std::mem::size_of::<(Option<Key>, Value)>();
+
+impl Object {
+    /// Approximate number of bytes owned by this object, used to drive
+    /// garbage collection.
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Object>()
+            + match self {
+                Object::String(s) => s.chars.capacity(),
+                Object::Function(f) => {
+                    f.chunk.code.capacity()
+                        + f.chunk.lines.capacity() * std::mem::size_of::<LineStart>()
+                        + f.chunk.spans.capacity()
+                        + f.chunk.constants.values.capacity() * std::mem::size_of::<Value>()
+                }
+                Object::Closure(c) => c.upvalues.capacity() * std::mem::size_of::<ObjRef>(),
+                Object::Upvalue(_) | Object::BoundMethod(_) | Object::Native(_) => 0,
+                Object::List(l) => l.items.capacity() * std::mem::size_of::<Value>(),
+                Object::Map(m) => m.entries.capacity() * TABLE_ENTRY_SIZE,
+                Object::Class(c) => c.methods.capacity() * TABLE_ENTRY_SIZE,
+                Object::Instance(i) => i.fields.capacity() * TABLE_ENTRY_SIZE,
+            }
+    }
+}
+
+/// An interned string. Two `ObjString`s never hold the same characters, so
+/// handles can be compared instead of contents.
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+impl ObjString {
+    pub fn new(chars: String) -> ObjString {
+        let hash = hash_string(&chars);
+        ObjString { chars, hash }
+    }
+}
+
+/// FNV-1a.
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash = 2166136261u32;
+    for &byte in chars.as_bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<String>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<String>) -> ObjFunction {
+        ObjFunction {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl ObjClosure {
+    pub fn new(function: ObjRef, upvalues: Vec<ObjRef>) -> ObjClosure {
+        ObjClosure { function, upvalues }
+    }
+}
+
+/// A captured variable. While open it refers to a live VM stack slot; once
+/// that slot goes away the value is moved into `closed`.
+#[derive(Debug)]
+pub struct ObjUpvalue {
+    pub location: usize,
+    pub closed: Option<Value>,
+}
+
+impl ObjUpvalue {
+    pub fn new(location: usize) -> ObjUpvalue {
+        ObjUpvalue {
+            location,
+            closed: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: String,
+    /// Method closures keyed by name.
+    pub methods: Table,
+}
+
+impl ObjClass {
+    pub fn new(name: String) -> ObjClass {
+        ObjClass {
+            name,
+            methods: Table::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: Table,
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> ObjInstance {
+        ObjInstance {
+            class,
+            fields: Table::new(),
+        }
+    }
+}
+
+/// A method closure paired with the instance it was accessed on.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: ObjRef) -> ObjBoundMethod {
+        ObjBoundMethod { receiver, method }
+    }
+}
+
+/// A function implemented in Rust. Errors are reported as runtime errors.
+pub type NativeFn = Rc<dyn Fn(&mut Vm, &[Value]) -> Result<Value, String>>;
+
+pub struct ObjNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl std::fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjNative")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ObjNative {
+    pub fn new(name: String, arity: usize, function: NativeFn) -> ObjNative {
+        ObjNative {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjList {
+    pub items: Vec<Value>,
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> ObjList {
+        ObjList { items }
+    }
+}
+
+/// Iterates in table order, not insertion order.
+#[derive(Debug)]
+pub struct ObjMap {
+    pub entries: Table,
+}
+
+impl ObjMap {
+    pub fn new() -> ObjMap {
+        ObjMap {
+            entries: Table::new(),
+        }
+    }
+}
+
+/// Appends the text `print` shows for `object` to `out`. `enclosing` holds
+/// the collections currently being written, so a collection that contains
+/// itself is shown as `[...]` or `{...}` instead of recursing forever.
+pub fn write_object(heap: &Heap, object: ObjRef, out: &mut String, enclosing: &mut Vec<ObjRef>) {
+    match heap.get(object) {
+        Object::String(s) => out.push_str(&s.chars),
+        Object::Function(f) => write_function(f, out),
+        Object::Closure(c) => write_function(heap.as_function(c.function), out),
+        Object::Upvalue(_) => out.push_str("upvalue"),
+        Object::Class(c) => out.push_str(&c.name),
+        Object::Instance(i) => write!(out, "{} instance", heap.as_class(i.class).name).unwrap(),
+        Object::BoundMethod(b) => {
+            let closure = heap.as_closure(b.method);
+            write_function(heap.as_function(closure.function), out)
+        }
+        Object::Native(n) => write!(out, "<native fn {}>", n.name).unwrap(),
+        Object::List(l) => {
+            if enclosing.contains(&object) {
+                out.push_str("[...]");
+                return;
+            }
+
+            enclosing.push(object);
+            out.push('[');
+            for (i, item) in l.items.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(heap, item, out, enclosing);
+            }
+            out.push(']');
+            enclosing.pop();
+        }
+        Object::Map(m) => {
+            if enclosing.contains(&object) {
+                out.push_str("{...}");
+                return;
+            }
+
+            enclosing.push(object);
+            out.push('{');
+            for (i, (key, value)) in m.entries.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(heap, &key.value(), out, enclosing);
+                out.push_str(": ");
+                write_value(heap, &value, out, enclosing);
+            }
+            out.push('}');
+            enclosing.pop();
+        }
+    }
 }
 
-pub fn print_object(object: &Object) {
-    match object {
-        Object::String(s) => print!("{}", s),
+fn write_function(function: &ObjFunction, out: &mut String) {
+    match &function.name {
+        Some(name) => write!(out, "<fn {}>", name).unwrap(),
+        None => out.push_str("<script>"),
     }
 }
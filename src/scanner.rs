@@ -7,6 +7,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -26,6 +29,10 @@ pub enum TokenType {
     // Literals.
     Identifier,
     String,
+    /// A string part ending in `${`; an expression and more parts follow.
+    Interpolation,
+    /// The string part after the last embedded expression.
+    InterpolationEnd,
     Number,
     // Keywords.
     And,
@@ -49,37 +56,74 @@ pub enum TokenType {
     Eof,
 }
 
-pub struct Token {
+/// A byte range of the source together with the line and column it starts
+/// on. Lines and columns are 1-based, and columns count chars, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A token whose lexeme borrows from the source. Error tokens carry their
+/// message as the lexeme instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'src> {
     pub token_type: TokenType,
-    pub lexeme: String,
-    pub line: i32,
+    pub lexeme: &'src str,
+    pub span: Span,
 }
 
-impl Token {
-    pub fn none() -> Token {
+impl<'src> Token<'src> {
+    pub fn none() -> Token<'src> {
         Token {
             token_type: TokenType::Error,
-            lexeme: "".to_owned(),
-            line: 0,
+            lexeme: "",
+            span: Span::default(),
+        }
+    }
+
+    /// A token that does not appear in the source, such as the implicit
+    /// `this` and `super` names.
+    pub fn synthetic(text: &'static str) -> Token<'src> {
+        Token {
+            token_type: TokenType::Identifier,
+            lexeme: text,
+            span: Span::default(),
         }
     }
 }
 
+/// Scans UTF-8 source in a single pass. `start` and `current` are byte
+/// offsets and always sit on char boundaries.
 #[derive(Debug)]
-pub struct Scanner {
-    source: String,
+pub struct Scanner<'src> {
+    source: &'src str,
     start: usize,
     current: usize,
-    line: i32,
+    /// Position of `current`.
+    line: usize,
+    column: usize,
+    /// Position of `start`.
+    start_line: usize,
+    start_column: usize,
+    /// One entry per `${` we are inside of, counting the `{` that have been
+    /// opened since so the matching `}` can resume the string.
+    interpolations: Vec<usize>,
 }
 
-impl Scanner {
-    pub fn new(source: String) -> Scanner {
+impl<'src> Scanner<'src> {
+    pub fn new(source: &'src str) -> Scanner<'src> {
         Scanner {
             source,
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            interpolations: vec![],
         }
     }
 
@@ -87,47 +131,80 @@ impl Scanner {
         self.current >= self.source.len()
     }
 
-    fn make_token(&self, token_type: TokenType) -> Token {
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
+            line: self.start_line,
+            column: self.start_column,
+        }
+    }
+
+    /// The span of `len` bytes found `offset` bytes into the current token.
+    fn span_at(&self, offset: usize, len: usize) -> Span {
+        let mut line = self.start_line;
+        let mut column = self.start_column;
+        for c in self.source[self.start..self.start + offset].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        Span {
+            start: self.start + offset,
+            end: self.start + offset + len,
+            line,
+            column,
+        }
+    }
+
+    fn make_token(&self, token_type: TokenType) -> Token<'src> {
         Token {
             token_type,
-            lexeme: self.source[self.start..self.current].to_owned(),
-            line: self.line,
+            lexeme: &self.source[self.start..self.current],
+            span: self.span(),
         }
     }
 
-    fn error_token(&self, message: &str) -> Token {
+    fn error_token(&self, message: &'static str) -> Token<'src> {
         Token {
             token_type: TokenType::Error,
-            lexeme: message.to_owned(),
-            line: self.line,
+            lexeme: message,
+            span: self.span(),
         }
     }
 
+    fn rest(&self) -> std::str::Chars<'src> {
+        self.source[self.current..].chars()
+    }
+
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.rest().next().unwrap();
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         c
     }
 
     fn peek(&self) -> char {
-        self.source.chars().nth(self.current).unwrap_or('\0')
+        self.rest().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current + 1).unwrap()
+        self.rest().nth(1).unwrap_or('\0')
     }
 
     fn match_char(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.advance();
         true
     }
 
@@ -135,21 +212,13 @@ impl Scanner {
         loop {
             let c = self.peek();
             match c {
-                ' ' | '\r' | '\t' => {
-                    let _ = self.advance();
-                }
-                '\n' => {
-                    self.line += 1;
-                    let _ = self.advance();
+                ' ' | '\r' | '\t' | '\n' => {
+                    self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        // A comment goes until the end of the line.
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    // A comment goes until the end of the line.
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -170,75 +239,106 @@ impl Scanner {
             return token_type;
         }
 
-        return TokenType::Identifier;
+        TokenType::Identifier
     }
 
     fn identifier_type(&self) -> TokenType {
-        // println!("{self:?}");
-        match self.source.chars().nth(self.start).unwrap() {
-            'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
-            'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            'f' => {
+        // Identifiers are ASCII, so bytes and chars coincide here.
+        let bytes = self.source.as_bytes();
+        match bytes[self.start] {
+            b'a' => self.check_keyword(1, 2, "nd", TokenType::And),
+            b'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            b'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
+            b'f' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'a' => self.check_keyword(2, 3, "lse", TokenType::False),
-                        'o' => self.check_keyword(2, 1, "r", TokenType::For),
-                        'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
+                    match bytes[self.start + 1] {
+                        b'a' => self.check_keyword(2, 3, "lse", TokenType::False),
+                        b'o' => self.check_keyword(2, 1, "r", TokenType::For),
+                        b'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
-            'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
-            'o' => self.check_keyword(1, 1, "r", TokenType::Or),
-            'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
-            'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
-            't' => {
+            b'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            b'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
+            b'o' => self.check_keyword(1, 1, "r", TokenType::Or),
+            b'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
+            b'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
+            b's' => self.check_keyword(1, 4, "uper", TokenType::Super),
+            b't' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                        'r' => self.check_keyword(2, 2, "ue", TokenType::True),
+                    match bytes[self.start + 1] {
+                        b'h' => self.check_keyword(2, 2, "is", TokenType::This),
+                        b'r' => self.check_keyword(2, 2, "ue", TokenType::True),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
-            'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+            b'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
+            b'w' => self.check_keyword(1, 4, "hile", TokenType::While),
             _ => TokenType::Identifier,
         }
     }
 
-    fn identifier(&mut self) -> Token {
+    fn identifier(&mut self) -> Token<'src> {
         while is_alpha(self.peek()) || is_digit(self.peek()) {
             self.advance();
         }
         self.make_token(self.identifier_type())
     }
 
-    fn string(&mut self) -> Token {
+    /// Scans a string literal, or the part of one up to an embedded `${`.
+    /// Escapes are only skipped over here; their meaning is decided by
+    /// `decode_string`.
+    fn string(&mut self, raw: bool) -> Token<'src> {
+        let resumed = self.source[self.start..].starts_with('}');
+        let mut token_type = if resumed {
+            TokenType::InterpolationEnd
+        } else {
+            TokenType::String
+        };
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if !raw && self.peek() == '$' && self.peek_next() == '{' {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                token_type = TokenType::Interpolation;
+                break;
+            }
+            if !raw && self.peek() == '\\' {
+                self.advance();
+                if self.is_at_end() {
+                    break;
+                }
             }
             self.advance();
         }
 
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.");
-        }
+        if token_type != TokenType::Interpolation {
+            if self.is_at_end() {
+                return self.error_token("Unterminated string.");
+            }
 
-        // The closing quote.
-        self.advance();
-        self.make_token(TokenType::String)
+            // The closing quote.
+            self.advance();
+        }
+        let token = self.make_token(token_type);
+        if let Err(error) = decode_string(token.lexeme) {
+            // Point at the bad escape rather than the whole string.
+            return Token {
+                token_type: TokenType::Error,
+                lexeme: error.message,
+                span: self.span_at(error.offset, 1),
+            };
+        }
+        token
     }
 
-    fn number(&mut self) -> Token {
+    fn number(&mut self) -> Token<'src> {
         while is_digit(self.peek()) {
             self.advance();
         }
@@ -256,15 +356,21 @@ impl Scanner {
         self.make_token(TokenType::Number)
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
 
         let c = self.advance();
+        if c == 'r' && self.peek() == '"' {
+            self.advance();
+            return self.string(true);
+        }
         if is_alpha(c) {
             return self.identifier();
         }
@@ -275,9 +381,28 @@ impl Scanner {
         match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    // The end of an embedded expression; the string resumes.
+                    self.interpolations.pop();
+                    self.string(false)
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
@@ -312,12 +437,111 @@ impl Scanner {
                     self.make_token(TokenType::Greater)
                 }
             }
-            '"' => self.string(),
+            '"' => self.string(false),
             _ => self.error_token("Unexpected character."),
         }
     }
 }
 
+/// Why `decode_string` rejected a literal: an escape sequence that is
+/// unknown, cut off by the end of the literal, or a malformed `\u{...}`.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[display(fmt = "{}", message)]
+pub struct StringError {
+    /// Byte offset of the escape's backslash into the lexeme.
+    pub offset: usize,
+    /// Describes the problem, e.g. "Invalid escape sequence.".
+    pub message: &'static str,
+}
+
+impl std::error::Error for StringError {}
+
+/// Turns a string literal lexeme, quotes included, into the string it
+/// denotes. Raw strings (`r"..."`) are taken verbatim; other strings support
+/// `\n`, `\t`, `\r`, `\\`, `\"`, `\0`, `\$` and `\u{XXXX}` escapes. Parts of
+/// an interpolated string are decoded the same way.
+///
+/// ```
+/// assert_eq!(rlox::decode_string(r#""caf\u{e9}\n""#).unwrap(), "caf\u{e9}\n");
+///
+/// let error = rlox::decode_string(r#""a\qb""#).unwrap_err();
+/// assert_eq!((error.offset, error.message), (2, "Invalid escape sequence."));
+/// ```
+pub fn decode_string(lexeme: &str) -> Result<String, StringError> {
+    if let Some(raw) = lexeme.strip_prefix("r\"") {
+        return Ok(raw[..raw.len() - 1].to_owned());
+    }
+
+    // Interpolated strings are split into parts that may start after a `}`
+    // and end before a `${` instead of at quotes.
+    let body = &lexeme[1..];
+    let body = body
+        .strip_suffix("${")
+        .unwrap_or_else(|| &body[..body.len() - 1]);
+    let mut decoded = String::with_capacity(body.len());
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+
+        // Offsets are reported relative to the lexeme, which starts with a quote.
+        let offset = i + 1;
+        let escaped = match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, 'r')) => '\r',
+            Some((_, '\\')) => '\\',
+            Some((_, '"')) => '"',
+            Some((_, '0')) => '\0',
+            Some((_, '$')) => '$',
+            Some((_, 'u')) => decode_unicode_escape(&mut chars, offset)?,
+            Some(_) => {
+                return Err(StringError {
+                    offset,
+                    message: "Invalid escape sequence.",
+                })
+            }
+            None => {
+                return Err(StringError {
+                    offset,
+                    message: "Unterminated escape sequence.",
+                })
+            }
+        };
+        decoded.push(escaped);
+    }
+    Ok(decoded)
+}
+
+/// Decodes the `{XXXX}` part of a `\u{XXXX}` escape starting at `offset`.
+fn decode_unicode_escape(
+    chars: &mut std::str::CharIndices,
+    offset: usize,
+) -> Result<char, StringError> {
+    let error = |message| StringError { offset, message };
+
+    if !matches!(chars.next(), Some((_, '{'))) {
+        return Err(error("Expect '{' after '\\u'."));
+    }
+
+    let mut digits = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '}')) => break,
+            Some((_, c)) if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+            _ => return Err(error("Unicode escape must be 1 to 6 hex digits in braces.")),
+        }
+    }
+    if digits.is_empty() {
+        return Err(error("Unicode escape must be 1 to 6 hex digits in braces."));
+    }
+
+    let code = u32::from_str_radix(&digits, 16).unwrap();
+    char::from_u32(code).ok_or_else(|| error("Invalid unicode code point in escape."))
+}
+
 fn is_alpha(c: char) -> bool {
     c.is_ascii_alphabetic() || c == '_'
 }
@@ -325,3 +549,196 @@ fn is_alpha(c: char) -> bool {
 fn is_digit(c: char) -> bool {
     c.is_ascii_digit()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(
+            decode_string(r#""a\n\t\r\\\"\0b""#),
+            Ok("a\n\t\r\\\"\0b".to_owned())
+        );
+        assert_eq!(
+            decode_string(r#""\u{48}\u{e9}\u{1F600}""#),
+            Ok("H\u{e9}\u{1F600}".to_owned())
+        );
+    }
+
+    #[test]
+    fn decodes_interpolation_parts() {
+        assert_eq!(decode_string(r#""total: ${"#), Ok("total: ".to_owned()));
+        assert_eq!(decode_string(r#"} and ${"#), Ok(" and ".to_owned()));
+        assert_eq!(decode_string(r#"}\${x}""#), Ok("${x}".to_owned()));
+    }
+
+    #[test]
+    fn raw_strings_keep_backslashes() {
+        assert_eq!(
+            decode_string(r#"r"a\n\u{48}""#),
+            Ok(r"a\n\u{48}".to_owned())
+        );
+    }
+
+    #[test]
+    fn reports_invalid_escapes_at_their_offset() {
+        let error = decode_string(r#""ab\qc""#).unwrap_err();
+        assert_eq!(error.offset, 3);
+        assert_eq!(error.message, "Invalid escape sequence.");
+
+        for bad in [
+            r#""\u48""#,
+            r#""\u{}""#,
+            r#""\u{1234567}""#,
+            r#""\u{D800}""#,
+        ] {
+            assert_eq!(decode_string(bad).unwrap_err().offset, 1, "{}", bad);
+        }
+    }
+
+    fn scan_all(source: &str) -> Vec<Token<'_>> {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = vec![];
+        loop {
+            let token = scanner.scan_token();
+            tokens.push(token);
+            if token.token_type == TokenType::Eof {
+                return tokens;
+            }
+        }
+    }
+
+    fn summarize<'a>(tokens: &[Token<'a>]) -> Vec<(TokenType, &'a str, usize)> {
+        tokens
+            .iter()
+            .map(|t| (t.token_type, t.lexeme, t.span.line))
+            .collect()
+    }
+
+    #[test]
+    fn multibyte_strings_share_a_line_with_code() {
+        let tokens = scan_all("var s = \"héllo 🌍\"; print s + \"ü\";");
+        assert_eq!(
+            summarize(&tokens),
+            vec![
+                (TokenType::Var, "var", 1),
+                (TokenType::Identifier, "s", 1),
+                (TokenType::Equal, "=", 1),
+                (TokenType::String, "\"héllo 🌍\"", 1),
+                (TokenType::Semicolon, ";", 1),
+                (TokenType::Print, "print", 1),
+                (TokenType::Identifier, "s", 1),
+                (TokenType::Plus, "+", 1),
+                (TokenType::String, "\"ü\"", 1),
+                (TokenType::Semicolon, ";", 1),
+                (TokenType::Eof, "", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn multibyte_comments_are_skipped() {
+        let tokens = scan_all("print 1; // ça marche — 日本語\nprint 2;");
+        assert_eq!(
+            summarize(&tokens),
+            vec![
+                (TokenType::Print, "print", 1),
+                (TokenType::Number, "1", 1),
+                (TokenType::Semicolon, ";", 1),
+                (TokenType::Print, "print", 2),
+                (TokenType::Number, "2", 2),
+                (TokenType::Semicolon, ";", 2),
+                (TokenType::Eof, "", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn multibyte_interpolation_parts() {
+        let tokens = scan_all("\"→${x}←\" == y");
+        assert_eq!(
+            summarize(&tokens),
+            vec![
+                (TokenType::Interpolation, "\"→${", 1),
+                (TokenType::Identifier, "x", 1),
+                (TokenType::InterpolationEnd, "}←\"", 1),
+                (TokenType::EqualEqual, "==", 1),
+                (TokenType::Identifier, "y", 1),
+                (TokenType::Eof, "", 1),
+            ]
+        );
+        assert_eq!(decode_string(tokens[0].lexeme), Ok("→".to_owned()));
+        assert_eq!(decode_string(tokens[2].lexeme), Ok("←".to_owned()));
+    }
+
+    #[test]
+    fn multibyte_character_outside_a_string_is_one_error() {
+        let tokens = scan_all("a é b");
+        assert_eq!(
+            summarize(&tokens),
+            vec![
+                (TokenType::Identifier, "a", 1),
+                (TokenType::Error, "Unexpected character.", 1),
+                (TokenType::Identifier, "b", 1),
+                (TokenType::Eof, "", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_count_chars_and_spans_count_bytes() {
+        let tokens = scan_all("\"日本\" + x;\n  // ü\n  y");
+        let spans: Vec<Span> = tokens.iter().map(|t| t.span).collect();
+        assert_eq!(
+            spans[..5],
+            [
+                Span {
+                    start: 0,
+                    end: 8,
+                    line: 1,
+                    column: 1
+                },
+                Span {
+                    start: 9,
+                    end: 10,
+                    line: 1,
+                    column: 6
+                },
+                Span {
+                    start: 11,
+                    end: 12,
+                    line: 1,
+                    column: 8
+                },
+                Span {
+                    start: 12,
+                    end: 13,
+                    line: 1,
+                    column: 9
+                },
+                Span {
+                    start: 24,
+                    end: 25,
+                    line: 3,
+                    column: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_escape_errors_point_at_the_escape() {
+        let tokens = scan_all("print \"ok\nü \\q\";");
+        assert_eq!(tokens[1].token_type, TokenType::Error);
+        assert_eq!(
+            tokens[1].span,
+            Span {
+                start: 13,
+                end: 14,
+                line: 2,
+                column: 3
+            }
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use crate::{
+    memory::{Heap, ObjRef},
+    object::Object,
+    value::{hash_value, values_equal, Value},
+};
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+/// A hashable value paired with its precomputed hash. Only nil, booleans,
+/// numbers other than NaN and strings can be keys; since strings are
+/// interned, every key compares by value without touching the heap.
+#[derive(Debug, Clone, Copy)]
+pub struct Key {
+    value: Value,
+    pub hash: u32,
+}
+
+impl Key {
+    pub fn string(string: ObjRef, hash: u32) -> Key {
+        Key {
+            value: Value::object(string),
+            hash,
+        }
+    }
+
+    /// Builds a key for an arbitrary value, failing for values that can't be
+    /// hashed consistently with `values_equal`.
+    pub fn from_value(heap: &Heap, value: Value) -> Result<Key, &'static str> {
+        if value.is_number() && value.as_number_unchecked().is_nan() {
+            return Err("Map keys can't be NaN.");
+        }
+        if value.is_object() && !matches!(heap.get(value.as_object()), Object::String(_)) {
+            return Err("Map keys must be strings, numbers, booleans or nil.");
+        }
+
+        Ok(Key {
+            value,
+            hash: hash_value(heap, &value),
+        })
+    }
+
+    pub fn value(&self) -> Value {
+        self.value
+    }
+
+    /// The key's string handle. Only valid for string keys.
+    pub fn as_string(&self) -> ObjRef {
+        self.value.as_object()
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.hash == other.hash && values_equal(&self.value, &other.value)
+    }
+}
+
+impl Eq for Key {}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    key: Option<Key>,
+    value: Value,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        key: None,
+        value: Value::nil(),
+    };
+
+    /// A deleted entry keeps probe sequences intact until the table grows.
+    const TOMBSTONE: Entry = Entry {
+        key: None,
+        value: Value::boolean(true),
+    };
+
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && !self.value.is_nil()
+    }
+}
+
+/// Open-addressing hash table with linear probing. Backs globals, fields,
+/// methods, the intern pool and map objects.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    /// Live entries plus tombstones; this is what the load factor limits.
+    count: usize,
+    /// Live entries only.
+    len: usize,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table {
+            count: 0,
+            len: 0,
+            entries: vec![],
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of live entries.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Capacity to rehash into once the load factor is exceeded. If most of
+    /// the load is tombstones, rehashing at the same size is enough.
+    fn grow_capacity(&self) -> usize {
+        let capacity = self.capacity();
+        if capacity < 8 {
+            8
+        } else if (self.len + 1) as f64 > capacity as f64 * TABLE_MAX_LOAD / 2.0 {
+            capacity * 2
+        } else {
+            capacity
+        }
+    }
+
+    fn find_entry(entries: &[Entry], key: Key) -> usize {
+        let capacity = entries.len();
+        let mut index = key.hash as usize % capacity;
+        let mut tombstone = None;
+
+        loop {
+            let entry = &entries[index];
+            match entry.key {
+                None if entry.is_tombstone() => {
+                    // Keep looking, but reuse the first tombstone we passed.
+                    tombstone.get_or_insert(index);
+                }
+                None => return tombstone.unwrap_or(index),
+                Some(existing) if existing == key => return index,
+                Some(_) => {}
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::EMPTY; capacity];
+
+        self.count = 0;
+        for entry in &self.entries {
+            let Some(key) = entry.key else {
+                continue;
+            };
+
+            let index = Self::find_entry(&entries, key);
+            entries[index] = *entry;
+            self.count += 1;
+        }
+
+        self.entries = entries;
+    }
+
+    pub fn get(&self, key: Key) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[Self::find_entry(&self.entries, key)];
+        entry.key.map(|_| entry.value)
+    }
+
+    /// Inserts or overwrites `key`. Returns true if the key was not present.
+    pub fn set(&mut self, key: Key, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.capacity() as f64 * TABLE_MAX_LOAD {
+            self.adjust_capacity(self.grow_capacity());
+        }
+
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        if is_new_key {
+            self.len += 1;
+            if !entry.is_tombstone() {
+                self.count += 1;
+            }
+        }
+
+        *entry = Entry {
+            key: Some(key),
+            value,
+        };
+        is_new_key
+    }
+
+    /// Removes `key`, leaving a tombstone behind. Returns true if it was present.
+    pub fn delete(&mut self, key: Key) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        let index = Self::find_entry(&self.entries, key);
+        if self.entries[index].key.is_none() {
+            return false;
+        }
+
+        self.entries[index] = Entry::TOMBSTONE;
+        self.len -= 1;
+        true
+    }
+
+    /// Copies every entry of `self` into `to`, overwriting existing keys.
+    pub fn add_all(&self, to: &mut Table) {
+        for (key, value) in self.iter() {
+            to.set(key, value);
+        }
+    }
+
+    /// Looks a string up by contents rather than by handle. This is how the
+    /// intern pool finds an existing string before a new one is allocated.
+    pub fn find_string(&self, heap: &Heap, chars: &str, hash: u32) -> Option<ObjRef> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let capacity = self.capacity();
+        let mut index = hash as usize % capacity;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                // Stop if we find an empty non-tombstone entry.
+                None if !entry.is_tombstone() => return None,
+                Some(key) if key.hash == hash && heap.as_string(key.as_string()).chars == chars => {
+                    return Some(key.as_string())
+                }
+                _ => {}
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    /// Deletes every entry whose key `keep` rejects.
+    pub fn retain(&mut self, mut keep: impl FnMut(Key) -> bool) {
+        for entry in self.entries.iter_mut() {
+            if let Some(key) = entry.key {
+                if !keep(key) {
+                    *entry = Entry::TOMBSTONE;
+                    self.len -= 1;
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, entry.value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(heap: &mut Heap, chars: &str) -> Key {
+        let string = heap.intern(chars, &|_| {});
+        heap.key(string)
+    }
+
+    fn number(value: Option<Value>) -> Option<f64> {
+        value.map(|value| {
+            assert!(value.is_number(), "expected a number");
+            value.as_number_unchecked()
+        })
+    }
+
+    #[test]
+    fn get_on_empty_table() {
+        let mut heap = Heap::new();
+        let table = Table::new();
+        assert!(table.get(key(&mut heap, "missing")).is_none());
+    }
+
+    #[test]
+    fn set_get_and_overwrite() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let a = key(&mut heap, "a");
+        let b = key(&mut heap, "b");
+
+        assert!(table.set(a, Value::number(1.0)));
+        assert!(table.set(b, Value::number(2.0)));
+        assert!(!table.set(a, Value::number(3.0)));
+
+        assert_eq!(number(table.get(a)), Some(3.0));
+        assert_eq!(number(table.get(b)), Some(2.0));
+        assert_eq!(table.iter().count(), 2);
+    }
+
+    #[test]
+    fn delete_leaves_other_keys_reachable() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let keys: Vec<Key> = (0..6).map(|i| key(&mut heap, &format!("k{i}"))).collect();
+        for (i, &k) in keys.iter().enumerate() {
+            table.set(k, Value::number(i as f64));
+        }
+
+        assert!(table.delete(keys[2]));
+        assert!(!table.delete(keys[2]));
+        assert!(table.get(keys[2]).is_none());
+        for (i, &k) in keys.iter().enumerate().filter(|&(i, _)| i != 2) {
+            assert_eq!(number(table.get(k)), Some(i as f64));
+        }
+
+        // Reinserting reuses the tombstone.
+        assert!(table.set(keys[2], Value::number(20.0)));
+        assert_eq!(number(table.get(keys[2])), Some(20.0));
+    }
+
+    #[test]
+    fn grows_past_load_factor() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let keys: Vec<Key> = (0..1000).map(|i| key(&mut heap, &i.to_string())).collect();
+        for (i, &k) in keys.iter().enumerate() {
+            table.set(k, Value::number(i as f64));
+        }
+
+        assert!(table.capacity() as f64 * TABLE_MAX_LOAD >= 1000.0);
+        for (i, &k) in keys.iter().enumerate() {
+            assert_eq!(number(table.get(k)), Some(i as f64));
+        }
+    }
+
+    #[test]
+    fn insert_delete_churn() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let keys: Vec<Key> = (0..64)
+            .map(|i| key(&mut heap, &format!("churn{i}")))
+            .collect();
+        let mut expected: Vec<Option<f64>> = vec![None; keys.len()];
+
+        // A simple deterministic generator keeps the sequence reproducible.
+        let mut state = 12345u32;
+        for round in 0..20_000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let slot = (state >> 16) as usize % keys.len();
+            if state & 1 == 0 {
+                let was_new = table.set(keys[slot], Value::number(round as f64));
+                assert_eq!(was_new, expected[slot].is_none());
+                expected[slot] = Some(round as f64);
+            } else {
+                assert_eq!(table.delete(keys[slot]), expected[slot].is_some());
+                expected[slot] = None;
+            }
+        }
+
+        for (slot, &k) in keys.iter().enumerate() {
+            assert_eq!(number(table.get(k)), expected[slot]);
+        }
+        let live = expected.iter().filter(|value| value.is_some()).count();
+        assert_eq!(table.iter().count(), live);
+        assert_eq!(table.len(), live);
+        // Tombstones count towards the load, so churn must not fill the table.
+        assert!(table.capacity() <= 256);
+    }
+
+    #[test]
+    fn add_all_copies_entries() {
+        let mut heap = Heap::new();
+        let mut from = Table::new();
+        let mut to = Table::new();
+        let a = key(&mut heap, "a");
+        let b = key(&mut heap, "b");
+        from.set(a, Value::number(1.0));
+        from.set(b, Value::number(2.0));
+        to.set(a, Value::number(10.0));
+
+        from.add_all(&mut to);
+
+        assert_eq!(number(to.get(a)), Some(1.0));
+        assert_eq!(number(to.get(b)), Some(2.0));
+        assert_eq!(to.iter().count(), 2);
+    }
+
+    #[test]
+    fn find_string_matches_contents() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let hello = key(&mut heap, "hello");
+        table.set(hello, Value::nil());
+
+        assert_eq!(
+            table.find_string(&heap, "hello", hello.hash),
+            Some(hello.as_string())
+        );
+        let other = crate::object::hash_string("world");
+        assert_eq!(table.find_string(&heap, "world", other), None);
+    }
+
+    #[test]
+    fn retain_drops_rejected_keys() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let a = key(&mut heap, "a");
+        let b = key(&mut heap, "b");
+        table.set(a, Value::nil());
+        table.set(b, Value::nil());
+
+        table.retain(|k| k == a);
+
+        assert!(table.get(a).is_some());
+        assert!(table.get(b).is_none());
+        assert_eq!(table.iter().count(), 1);
+    }
+}
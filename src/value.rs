@@ -1,11 +1,187 @@
-use crate::object::{print_object, Object};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Write,
+    hash::{Hash, Hasher},
+};
 
-#[derive(Debug, Clone)]
-pub enum Value {
-    Nil,
-    Boolean(bool),
-    Number(f64),
-    Object(Object),
+use crate::{
+    memory::{Heap, ObjRef},
+    object::{write_object, Object},
+};
+
+pub use repr::Value;
+
+/// Tagged-union layout: each value carries its variant alongside the payload.
+#[cfg(not(feature = "nan-boxing"))]
+mod repr {
+    use crate::memory::ObjRef;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Repr {
+        Nil,
+        Boolean(bool),
+        Number(f64),
+        Object(ObjRef),
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct Value(Repr);
+
+    impl Value {
+        pub const fn nil() -> Value {
+            Value(Repr::Nil)
+        }
+
+        pub const fn boolean(b: bool) -> Value {
+            Value(Repr::Boolean(b))
+        }
+
+        pub const fn number(n: f64) -> Value {
+            Value(Repr::Number(n))
+        }
+
+        pub(crate) const fn object(object: ObjRef) -> Value {
+            Value(Repr::Object(object))
+        }
+
+        pub fn is_nil(&self) -> bool {
+            matches!(self.0, Repr::Nil)
+        }
+
+        pub fn is_bool(&self) -> bool {
+            matches!(self.0, Repr::Boolean(_))
+        }
+
+        pub fn is_number(&self) -> bool {
+            matches!(self.0, Repr::Number(_))
+        }
+
+        pub fn is_object(&self) -> bool {
+            matches!(self.0, Repr::Object(_))
+        }
+
+        pub(crate) fn as_bool_unchecked(&self) -> bool {
+            match self.0 {
+                Repr::Boolean(b) => b,
+                _ => unreachable!(),
+            }
+        }
+
+        pub(crate) fn as_number_unchecked(&self) -> f64 {
+            match self.0 {
+                Repr::Number(n) => n,
+                _ => unreachable!(),
+            }
+        }
+
+        pub(crate) fn as_object(&self) -> ObjRef {
+            match self.0 {
+                Repr::Object(object) => object,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// NaN-boxed layout: numbers are stored as their raw bits and every other
+/// value lives in the payload of a quiet NaN, so a value is a single `u64`.
+#[cfg(feature = "nan-boxing")]
+mod repr {
+    use crate::memory::ObjRef;
+
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    const NIL_VAL: u64 = QNAN | TAG_NIL;
+    const FALSE_VAL: u64 = QNAN | TAG_FALSE;
+    const TRUE_VAL: u64 = QNAN | TAG_TRUE;
+
+    #[derive(Clone, Copy)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub const fn nil() -> Value {
+            Value(NIL_VAL)
+        }
+
+        pub const fn boolean(b: bool) -> Value {
+            if b {
+                Value(TRUE_VAL)
+            } else {
+                Value(FALSE_VAL)
+            }
+        }
+
+        pub const fn number(n: f64) -> Value {
+            Value(n.to_bits())
+        }
+
+        pub(crate) const fn object(object: ObjRef) -> Value {
+            Value(SIGN_BIT | QNAN | object.to_raw())
+        }
+
+        pub fn is_nil(&self) -> bool {
+            self.0 == NIL_VAL
+        }
+
+        pub fn is_bool(&self) -> bool {
+            // TRUE_VAL and FALSE_VAL differ only in the lowest bit.
+            (self.0 | 1) == TRUE_VAL
+        }
+
+        pub fn is_number(&self) -> bool {
+            (self.0 & QNAN) != QNAN
+        }
+
+        pub fn is_object(&self) -> bool {
+            (self.0 & (QNAN | SIGN_BIT)) == (QNAN | SIGN_BIT)
+        }
+
+        pub(crate) fn as_bool_unchecked(&self) -> bool {
+            self.0 == TRUE_VAL
+        }
+
+        pub(crate) fn as_number_unchecked(&self) -> f64 {
+            f64::from_bits(self.0)
+        }
+
+        pub(crate) fn as_object(&self) -> ObjRef {
+            ObjRef::from_raw(self.0 & !(SIGN_BIT | QNAN))
+        }
+    }
+
+    impl std::fmt::Debug for Value {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            if self.is_nil() {
+                write!(f, "Nil")
+            } else if self.is_bool() {
+                write!(f, "Boolean({})", self.as_bool_unchecked())
+            } else if self.is_number() {
+                write!(f, "Number({})", self.as_number_unchecked())
+            } else {
+                write!(f, "Object({:?})", self.as_object())
+            }
+        }
+    }
+}
+
+// The `_unchecked` accessors of both layouts assume the caller already
+// checked the variant: the tagged layout panics otherwise, while the NaN-boxed
+// one returns garbage.
+impl Value {
+    /// The number this value holds, if it is one.
+    pub fn as_number(&self) -> Option<f64> {
+        self.is_number().then(|| self.as_number_unchecked())
+    }
+
+    /// The boolean this value holds, if it is one.
+    pub fn as_bool(&self) -> Option<bool> {
+        self.is_bool().then(|| self.as_bool_unchecked())
+    }
 }
 
 #[derive(Debug)]
@@ -23,27 +199,132 @@ impl ValueArray {
     }
 }
 
-pub fn print_value(value: &Value) {
-    match value {
-        Value::Boolean(b) => print!("{}", b),
-        Value::Number(n) => print!("{}", n),
-        Value::Nil => print!("nil"),
-        Value::Object(o) => print_object(o),
+pub fn print_value(heap: &Heap, value: &Value) {
+    print!("{}", format_value(heap, value));
+}
+
+/// The text `print` shows for `value`.
+pub fn format_value(heap: &Heap, value: &Value) -> String {
+    let mut out = String::new();
+    write_value(heap, value, &mut out, &mut vec![]);
+    out
+}
+
+/// Appends `value` to `out`; see `write_object` for `enclosing`.
+pub fn write_value(heap: &Heap, value: &Value, out: &mut String, enclosing: &mut Vec<ObjRef>) {
+    if value.is_bool() {
+        write!(out, "{}", value.as_bool_unchecked()).unwrap();
+    } else if value.is_number() {
+        write!(out, "{}", value.as_number_unchecked()).unwrap();
+    } else if value.is_nil() {
+        out.push_str("nil");
+    } else {
+        write_object(heap, value.as_object(), out, enclosing);
     }
 }
 
 pub fn values_equal(a: &Value, b: &Value) -> bool {
-    match (a, b) {
-        (Value::Nil, Value::Nil) => true,
-        (Value::Boolean(a), Value::Boolean(b)) => a == b,
-        (Value::Number(a), Value::Number(b)) => a == b,
-        (Value::Object(a), Value::Object(b)) => {
-            if let (Object::String(a), Object::String(b)) = (a, b) {
-                a == b
-            } else {
-                false
+    if a.is_number() && b.is_number() {
+        // Compare as floats so NaN != NaN regardless of the layout.
+        a.as_number_unchecked() == b.as_number_unchecked()
+    } else if a.is_bool() && b.is_bool() {
+        a.as_bool_unchecked() == b.as_bool_unchecked()
+    } else if a.is_nil() && b.is_nil() {
+        true
+    } else if a.is_object() && b.is_object() {
+        // Strings are interned, so identity is equality for every object.
+        a.as_object() == b.as_object()
+    } else {
+        false
+    }
+}
+
+/// Hash consistent with `values_equal`: `0` and `-0` hash alike and strings
+/// hash by contents. NaN never equals itself, so its hash is meaningless and
+/// callers must not use it as a key.
+pub fn hash_value(heap: &Heap, value: &Value) -> u32 {
+    if value.is_nil() {
+        0
+    } else if value.is_bool() {
+        if value.as_bool_unchecked() {
+            1
+        } else {
+            2
+        }
+    } else if value.is_number() {
+        // Adding zero turns -0 into 0.
+        let bits = (value.as_number_unchecked() + 0.0).to_bits();
+        (bits ^ (bits >> 32)) as u32
+    } else {
+        match heap.get(value.as_object()) {
+            Object::String(s) => s.hash,
+            _ => {
+                let mut hasher = DefaultHasher::new();
+                value.as_object().hash(&mut hasher);
+                hasher.finish() as u32
             }
         }
-        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_kind() {
+        let mut heap = Heap::new();
+        let string = heap.intern("boxed", &|_| {});
+
+        assert!(Value::nil().is_nil());
+        assert_eq!(Value::boolean(true).as_bool(), Some(true));
+        assert_eq!(Value::boolean(false).as_bool(), Some(false));
+        assert_eq!(Value::number(-2.5).as_number(), Some(-2.5));
+        assert_eq!(Value::object(string).as_object(), string);
+
+        for value in [
+            Value::nil(),
+            Value::boolean(false),
+            Value::number(0.0),
+            Value::object(string),
+        ] {
+            let kinds = [
+                value.is_nil(),
+                value.is_bool(),
+                value.is_number(),
+                value.is_object(),
+            ];
+            assert_eq!(kinds.iter().filter(|&&kind| kind).count(), 1);
+        }
+
+        // Checked accessors agree between layouts on the wrong variant.
+        for value in [Value::nil(), Value::number(1.0), Value::object(string)] {
+            assert_eq!(value.as_bool(), None);
+        }
+        for value in [Value::nil(), Value::boolean(true), Value::object(string)] {
+            assert_eq!(value.as_number(), None);
+        }
+    }
+
+    #[test]
+    fn equal_values_hash_alike() {
+        let heap = Heap::new();
+        let hash = |value| hash_value(&heap, &value);
+
+        assert_eq!(hash(Value::number(0.0)), hash(Value::number(-0.0)));
+        assert_eq!(hash(Value::number(1.5)), hash(Value::number(1.5)));
+        assert_ne!(hash(Value::boolean(true)), hash(Value::boolean(false)));
+    }
+
+    #[test]
+    fn equality_follows_lox_semantics() {
+        assert!(values_equal(&Value::nil(), &Value::nil()));
+        assert!(!values_equal(&Value::nil(), &Value::boolean(false)));
+        assert!(!values_equal(&Value::number(0.0), &Value::boolean(false)));
+        assert!(values_equal(&Value::number(0.0), &Value::number(-0.0)));
+        assert!(!values_equal(
+            &Value::number(f64::NAN),
+            &Value::number(f64::NAN)
+        ));
     }
 }
@@ -1,208 +1,1651 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    rc::{Rc, Weak},
+};
+
 use crate::{
-    chunk::{
-        Chunk,
-        OpCode::{self, *},
-    },
+    chunk::{Chunk, DecodeError, Instruction},
     compiler::compile,
+    convert::IntoNative,
     debug::disassemble_instruction,
-    object::Object,
-    value::{print_value, values_equal, Value},
+    error::{LoxError, RuntimeError, RuntimeErrorKind, StackFrame},
+    memory::{Heap, ObjRef},
+    native::define_natives,
+    object::{
+        NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjList, ObjMap, ObjNative,
+        ObjUpvalue, Object,
+    },
+    scanner::Span,
+    table::{Key, Table},
+    value::{format_value, print_value, values_equal, write_value, Value},
 };
 
+use RuntimeErrorKind::*;
+
+const FRAMES_MAX: usize = 64;
+
+/// A Lox interpreter. Globals, including natives, live as long as the VM.
 #[derive(Debug)]
-pub struct VM {
-    chunk: Chunk,
-    ip: usize,
+pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    stack_: Vec<Value>,
+    globals: Table,
+    init_string: Key,
+    /// Upvalues still pointing at live stack slots, sorted by slot.
+    open_upvalues: Vec<ObjRef>,
+    /// Functions handed out as `Script`s, kept alive while any clone of
+    /// their `Script` is.
+    scripts: Vec<Weak<ObjRef>>,
+    output: Output,
+    trace_execution: bool,
+    print_code: bool,
+    heap: Heap,
+}
+
+/// Where `print` statements write.
+struct Output(Box<dyn Write>);
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Output")
+    }
 }
 
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+/// Options for creating a `Vm`.
+#[derive(Debug, Default, Clone)]
+pub struct VmBuilder {
+    gc_stress: bool,
+    trace_execution: bool,
+    print_code: bool,
 }
 
-impl VM {
-    pub fn new(chunk: Chunk) -> VM {
-        VM {
-            chunk,
-            ip: 0,
+impl VmBuilder {
+    pub fn new() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    /// Collect garbage on every allocation to flush out missing roots.
+    pub fn gc_stress(mut self, gc_stress: bool) -> VmBuilder {
+        self.gc_stress = gc_stress;
+        self
+    }
+
+    /// Print the stack and each instruction to stdout as it runs.
+    pub fn trace_execution(mut self, trace_execution: bool) -> VmBuilder {
+        self.trace_execution = trace_execution;
+        self
+    }
+
+    /// Print the bytecode of every function to stdout once it compiles.
+    pub fn print_code(mut self, print_code: bool) -> VmBuilder {
+        self.print_code = print_code;
+        self
+    }
+
+    pub fn build(self) -> Vm {
+        let mut heap = Heap::new();
+        heap.stress = self.gc_stress;
+        // Nothing to collect yet, so there are no roots to mark.
+        let init_string = heap.intern("init", &|_| {});
+        let init_string = heap.key(init_string);
+
+        let mut vm = Vm {
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: vec![],
-            stack_: vec![],
+            globals: Table::new(),
+            open_upvalues: vec![],
+            scripts: vec![],
+            init_string,
+            output: Output(Box::new(io::stdout())),
+            trace_execution: self.trace_execution,
+            print_code: self.print_code,
+            heap,
+        };
+        define_natives(&mut vm);
+        vm
+    }
+}
+
+/// A compiled top-level script. Only valid with the `Vm` that compiled it,
+/// which keeps the script's code alive until every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct Script {
+    function: Rc<ObjRef>,
+}
+
+#[derive(Debug)]
+struct CallFrame {
+    closure: ObjRef,
+    function: ObjRef,
+    ip: usize,
+    /// Index of the frame's first stack slot (the callee itself).
+    slots: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        VmBuilder::new().build()
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+
+    /// Drops the frames and stack slots above the given depths, such as
+    /// those left behind by a run that failed.
+    fn unwind(&mut self, stack_len: usize, frame_count: usize) {
+        self.close_upvalues(stack_len);
+        self.stack.truncate(stack_len);
+        self.frames.truncate(frame_count);
+    }
+
+    /// Compiles and runs `source`, returning the value the script returns.
+    /// Globals persist between calls; the stack does not survive an error.
+    pub fn interpret(&mut self, source: &str) -> Result<Value, LoxError> {
+        let function = self.compile_function(source)?;
+        self.run_function(function)
+    }
+
+    /// Compiles `source` without running it.
+    pub fn compile(&mut self, source: &str) -> Result<Script, LoxError> {
+        let function = Rc::new(self.compile_function(source)?);
+        // Forget scripts that have been dropped.
+        self.scripts.retain(|script| script.strong_count() > 0);
+        self.scripts.push(Rc::downgrade(&function));
+        Ok(Script { function })
+    }
+
+    pub fn run(&mut self, script: &Script) -> Result<Value, LoxError> {
+        let function = self.own_script(script)?;
+        self.run_function(function)
+    }
+
+    /// The bytecode of a compiled script's top-level code.
+    pub fn chunk(&self, script: &Script) -> Result<&Chunk, LoxError> {
+        let function = self.own_script(script)?;
+        Ok(&self.heap.as_function(function).chunk)
+    }
+
+    /// Sends the output of `print` statements to `output` instead of stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Output(Box::new(output));
+    }
+
+    /// The text `print` would show for `value`. An object value is only kept
+    /// alive while the VM can reach it; once it has been collected this
+    /// returns `LoxError::StaleHandle`.
+    pub fn format_value(&self, value: &Value) -> Result<String, LoxError> {
+        self.check_handle(value)?;
+        Ok(format_value(&self.heap, value))
+    }
+
+    fn own_script(&self, script: &Script) -> Result<ObjRef, LoxError> {
+        let function = *script.function;
+        if self.heap.owns(function) {
+            Ok(function)
+        } else {
+            Err(LoxError::ForeignHandle)
         }
     }
 
-    fn reset_stack(&mut self) {
-        self.stack = self.stack_.clone();
+    /// Checks that an object `value` from the host belongs to this VM and
+    /// has not been collected since it was handed out.
+    fn check_handle(&self, value: &Value) -> Result<(), LoxError> {
+        if !value.is_object() {
+            Ok(())
+        } else if !self.heap.owns(value.as_object()) {
+            Err(LoxError::ForeignHandle)
+        } else if !self.heap.is_live(value.as_object()) {
+            Err(LoxError::StaleHandle)
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn interpret(&mut self, source: String) -> InterpretResult {
-        let Some(chunk) = compile(source) else {
-            return InterpretResult::CompileError;
+    fn compile_function(&mut self, source: &str) -> Result<ObjRef, LoxError> {
+        let vm_roots = |heap: &mut Heap| {
+            mark_roots(
+                heap,
+                &self.stack,
+                &self.frames,
+                &self.globals,
+                &self.open_upvalues,
+                &self.scripts,
+                self.init_string,
+            )
         };
+        compile(source, &mut self.heap, &vm_roots, self.print_code).map_err(LoxError::Compile)
+    }
+
+    /// Natives may call back into the VM, so a run can start while another
+    /// is in progress. It only executes and unwinds the frames it pushed.
+    fn run_function(&mut self, function: ObjRef) -> Result<Value, LoxError> {
+        let (stack_len, frame_count) = (self.stack.len(), self.frames.len());
+        self.push(Value::object(function));
+        let closure = self.alloc(Object::Closure(ObjClosure::new(function, vec![])));
+        self.pop();
+        self.push(Value::object(closure));
+
+        let result = self
+            .call(closure, 0)
+            .and_then(|()| self.execute(frame_count));
+        if result.is_err() {
+            self.unwind(stack_len, frame_count);
+        }
+        result.map_err(LoxError::Runtime)
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn current_chunk(&self) -> &Chunk {
+        &self.heap.as_function(self.frame().function).chunk
+    }
+
+    pub(crate) fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub(crate) fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Defines a global function `name` implemented by `function`, which
+    /// receives exactly `arity` arguments. An `Err` becomes a runtime error.
+    /// See `define_function` for natives with typed parameters.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut Vm, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        self.define_native_fn(name, arity, Rc::new(function));
+    }
 
-        self.chunk = chunk;
-        self.ip = 0;
+    /// Defines a global function `name` from an ordinary Rust closure. Its
+    /// parameter types give the arity and are checked on each call; see
+    /// `FromLox` and `IntoLox` for the supported types.
+    ///
+    /// ```
+    /// let mut vm = rlox::Vm::new();
+    /// vm.define_function("hypot", |a: f64, b: f64| a.hypot(b));
+    /// vm.interpret("print hypot(3, 4);").unwrap();
+    /// ```
+    pub fn define_function<Args>(&mut self, name: &str, function: impl IntoNative<Args>) {
+        let arity = function.arity();
+        let function = function.into_native(name.to_owned());
+        self.define_native_fn(name, arity, function);
+    }
 
-        self.run()
+    fn define_native_fn(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // Keep both objects on the stack while the other is allocated.
+        let name = self.take_string(name.to_owned());
+        self.push(Value::object(name));
+        let chars = self.heap.as_string(name).chars.clone();
+        let native = self.alloc(Object::Native(ObjNative::new(chars, arity, function)));
+        self.push(Value::object(native));
+        self.globals.set(self.heap.key(name), Value::object(native));
+        self.pop();
+        self.pop();
     }
 
-    fn run(&mut self) -> InterpretResult {
-        println!("{self:?}");
+    pub(crate) fn alloc(&mut self, object: Object) -> ObjRef {
+        let roots = |heap: &mut Heap| {
+            mark_roots(
+                heap,
+                &self.stack,
+                &self.frames,
+                &self.globals,
+                &self.open_upvalues,
+                &self.scripts,
+                self.init_string,
+            )
+        };
+        self.heap.alloc(object, &roots)
+    }
+
+    /// Accounts for `object` growing in place. It must still be reachable,
+    /// for example from the stack.
+    pub(crate) fn remeasure(&mut self, object: ObjRef) {
+        let roots = |heap: &mut Heap| {
+            mark_roots(
+                heap,
+                &self.stack,
+                &self.frames,
+                &self.globals,
+                &self.open_upvalues,
+                &self.scripts,
+                self.init_string,
+            )
+        };
+        self.heap.remeasure(object, &roots);
+    }
+
+    pub(crate) fn take_string(&mut self, chars: String) -> ObjRef {
+        let roots = |heap: &mut Heap| {
+            mark_roots(
+                heap,
+                &self.stack,
+                &self.frames,
+                &self.globals,
+                &self.open_upvalues,
+                &self.scripts,
+                self.init_string,
+            )
+        };
+        self.heap.take_string(chars, &roots)
+    }
 
+    /// Runs until the frame at `base` returns.
+    fn execute(&mut self, base: usize) -> Result<Value, RuntimeError> {
         loop {
-            if cfg!(debug_assertions) {
+            if self.trace_execution {
                 print!("          ");
                 for slot in self.stack.iter() {
                     print!("[ ");
-                    print_value(slot);
+                    print_value(&self.heap, slot);
                     print!(" ]");
                 }
                 println!();
-                disassemble_instruction(&self.chunk, self.ip);
+                disassemble_instruction(&self.heap, self.current_chunk(), self.frame().ip);
             }
-            let instruction = self.read_byte::<OpCode>();
+            let instruction = match self.read_instruction() {
+                Ok(instruction) => instruction,
+                Err(error) => {
+                    return Err(self.runtime_error(InvalidBytecode, error.to_string()));
+                }
+            };
 
             match instruction {
-                OP_CONSTANT => {
-                    let value = self.read_constant();
-                    print_value(&value);
+                Instruction::Constant(index) => {
+                    let value = self.read_constant(index as usize);
+                    self.push(value);
+                }
+                Instruction::ConstantLong(index) => {
+                    let value = self.read_constant(index as usize);
+                    self.push(value);
+                }
+                Instruction::Nil => self.push(Value::nil()),
+                Instruction::True => self.push(Value::boolean(true)),
+                Instruction::False => self.push(Value::boolean(false)),
+                Instruction::GetUpvalue(slot) => {
+                    let slot = slot as usize;
+                    let upvalue = self.heap.as_closure(self.frame().closure).upvalues[slot];
+                    let upvalue = self.heap.as_upvalue(upvalue);
+                    let value = match upvalue.closed {
+                        Some(value) => value,
+                        None => self.stack[upvalue.location],
+                    };
                     self.push(value);
-                    println!();
                 }
-                OP_NIL => self.push(Value::Nil),
-                OP_TRUE => self.push(Value::Boolean(true)),
-                OP_FALSE => self.push(Value::Boolean(false)),
-                OP_EQUAL => {
+                Instruction::SetUpvalue(slot) => {
+                    let slot = slot as usize;
+                    let value = self.peek(0);
+                    let upvalue = self.heap.as_closure(self.frame().closure).upvalues[slot];
+                    let upvalue = self.heap.as_upvalue_mut(upvalue);
+                    match &mut upvalue.closed {
+                        Some(closed) => *closed = value,
+                        None => self.stack[upvalue.location] = value,
+                    }
+                }
+                Instruction::GetProperty(index) => self.get_property(index.into())?,
+                Instruction::GetPropertyLong(index) => self.get_property(index)?,
+                Instruction::SetProperty(index) => self.set_property(index.into())?,
+                Instruction::SetPropertyLong(index) => self.set_property(index)?,
+                Instruction::GetSuper(index) => self.get_super(index.into())?,
+                Instruction::GetSuperLong(index) => self.get_super(index)?,
+                Instruction::Equal => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(values_equal(&a, &b)));
+                    self.push(Value::boolean(values_equal(&a, &b)));
+                }
+                Instruction::Greater => {
+                    self.binary_op(|a, b| Value::boolean(a > b))?;
                 }
-                OP_GREATER => self.binary_op(|a, b| Value::Boolean(a > b)),
-                OP_LESS => self.binary_op(|a, b| Value::Boolean(a < b)),
-                OP_ADD => {
+                Instruction::Less => {
+                    self.binary_op(|a, b| Value::boolean(a < b))?;
+                }
+                Instruction::Add => {
                     let peek0 = self.peek(0);
                     let peek1 = self.peek(1);
 
-                    if let (Value::Object(Object::String(_)), Value::Object(Object::String(_))) =
-                        (&peek0, &peek1)
-                    {
+                    if self.is_string(&peek0) && self.is_string(&peek1) {
                         self.concatenate();
-                    } else if let (Value::Number(_), Value::Number(_)) = (&peek0, &peek1) {
-                        let b = self.pop();
-                        let a = self.pop();
-                        match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => self.push(Value::Number(a + b)),
-                            _ => unreachable!(),
-                        }
+                    } else if peek0.is_number() && peek1.is_number() {
+                        let b = self.pop().as_number_unchecked();
+                        let a = self.pop().as_number_unchecked();
+                        self.push(Value::number(a + b));
                     } else {
-                        self.runtime_error("Operands must be two numbers or two strings.");
-                        return InterpretResult::RuntimeError;
+                        return Err(self
+                            .runtime_error(Type, "Operands must be two numbers or two strings."));
                     }
                 }
-                OP_SUBTRACT => self.binary_op(|a, b| Value::Number(a - b)),
-                OP_MULTIPLY => self.binary_op(|a, b| Value::Number(a * b)),
-                OP_DIVIDE => self.binary_op(|a, b| Value::Number(a / b)),
-                OP_NOT => {
+                Instruction::Subtract => {
+                    self.binary_op(|a, b| Value::number(a - b))?;
+                }
+                Instruction::Multiply => {
+                    self.binary_op(|a, b| Value::number(a * b))?;
+                }
+                Instruction::Divide => {
+                    self.binary_op(|a, b| Value::number(a / b))?;
+                }
+                Instruction::Not => {
+                    let value = self.pop();
+                    self.push(Value::boolean(Self::is_falsy(&value)));
+                }
+                Instruction::Negate => {
+                    if !self.peek(0).is_number() {
+                        return Err(self.runtime_error(Type, "Operand must be a number."));
+                    }
+                    let n = self.pop().as_number_unchecked();
+                    self.push(Value::number(-n));
+                }
+                Instruction::Print => {
                     let value = self.pop();
-                    self.push(Value::Boolean(Self::is_falsy(&value)));
+                    let text = format_value(&self.heap, &value);
+                    writeln!(self.output.0, "{}", text).expect("failed to write output");
+                }
+                Instruction::Pop => {
+                    self.pop();
+                }
+                Instruction::GetLocal(slot) => {
+                    let slot = slot as usize;
+                    let base = self.frame().slots;
+                    self.push(self.stack[base + slot]);
                 }
-                OP_NEGATE => match self.peek(0) {
-                    Value::Number(n) => self.push(Value::Number(-n)),
-                    _ => {
-                        self.runtime_error("Operand must be a number.");
-                        return InterpretResult::RuntimeError;
+                Instruction::SetLocal(slot) => {
+                    let slot = slot as usize;
+                    let base = self.frame().slots;
+                    self.stack[base + slot] = self.peek(0);
+                }
+                Instruction::GetGlobal(index) => self.get_global(index.into())?,
+                Instruction::GetGlobalLong(index) => self.get_global(index)?,
+                Instruction::DefineGlobal(index) => self.define_global(index.into()),
+                Instruction::DefineGlobalLong(index) => self.define_global(index),
+                Instruction::SetGlobal(index) => self.set_global(index.into())?,
+                Instruction::SetGlobalLong(index) => self.set_global(index)?,
+                Instruction::Jump(offset) => {
+                    self.frame_mut().ip += offset as usize;
+                }
+                Instruction::JumpIfFalse(offset) => {
+                    if Self::is_falsy(&self.peek(0)) {
+                        self.frame_mut().ip += offset as usize;
                     }
-                },
-                OP_RETURN => {
-                    print_value(&self.pop());
-                    println!();
-                    return InterpretResult::Ok;
                 }
-                unknown_opcode => {
-                    println!("Unknown opcode {:?}", unknown_opcode);
-                    return InterpretResult::CompileError;
+                Instruction::Loop(offset) => {
+                    self.frame_mut().ip -= offset as usize;
+                }
+                Instruction::Call(arg_count) => {
+                    self.call_value(self.peek(arg_count as usize), arg_count)?;
                 }
+                Instruction::Invoke { name, arg_count } => {
+                    self.invoke(self.read_string(name.into()), arg_count)?;
+                }
+                Instruction::InvokeLong { name, arg_count } => {
+                    self.invoke(self.read_string(name), arg_count)?;
+                }
+                Instruction::SuperInvoke { name, arg_count } => {
+                    self.super_invoke(name.into(), arg_count)?;
+                }
+                Instruction::SuperInvokeLong { name, arg_count } => {
+                    self.super_invoke(name, arg_count)?;
+                }
+                Instruction::Closure(index) => self.closure(index.into()),
+                Instruction::ClosureLong(index) => self.closure(index),
+                Instruction::CaptureLocal(index) => {
+                    let upvalue = self.capture_upvalue(self.frame().slots + index as usize);
+                    self.add_capture(upvalue);
+                }
+                Instruction::CaptureUpvalue(index) => {
+                    let upvalue =
+                        self.heap.as_closure(self.frame().closure).upvalues[index as usize];
+                    self.add_capture(upvalue);
+                }
+                Instruction::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Instruction::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.len() == base {
+                        return Ok(result);
+                    }
+
+                    self.push(result);
+                }
+                Instruction::Class(index) => self.class(index.into()),
+                Instruction::ClassLong(index) => self.class(index),
+                Instruction::Inherit => {
+                    let superclass = self.peek(1);
+                    if !superclass.is_object()
+                        || !matches!(self.heap.get(superclass.as_object()), Object::Class(_))
+                    {
+                        return Err(self.runtime_error(Type, "Superclass must be a class."));
+                    }
+                    let superclass = superclass.as_object();
+                    let subclass = self.peek(0).as_object();
+
+                    let methods = self.heap.as_class(superclass).methods.clone();
+                    methods.add_all(&mut self.heap.as_class_mut(subclass).methods);
+                    self.remeasure(subclass);
+                    self.pop(); // Subclass.
+                }
+                Instruction::BuildList(item_count) => {
+                    let first_item = self.stack.len() - item_count as usize;
+                    let items = self.stack[first_item..].to_vec();
+                    let list = self.alloc(Object::List(ObjList::new(items)));
+                    self.stack.truncate(first_item);
+                    self.push(Value::object(list));
+                }
+                Instruction::BuildMap(entry_count) => {
+                    let map = self.alloc(Object::Map(ObjMap::new()));
+                    let first_entry = self.stack.len() - 2 * entry_count as usize;
+                    // Keep the map reachable while the entries are added.
+                    self.push(Value::object(map));
+                    for i in 0..entry_count as usize {
+                        let key = self.map_key(self.stack[first_entry + 2 * i])?;
+                        let value = self.stack[first_entry + 2 * i + 1];
+                        self.heap.as_map_mut(map).entries.set(key, value);
+                    }
+                    self.remeasure(map);
+                    self.stack.truncate(first_entry);
+                    self.push(Value::object(map));
+                }
+                Instruction::BuildString(part_count) => {
+                    let first_part = self.stack.len() - part_count as usize;
+                    let mut result = String::new();
+                    for part in &self.stack[first_part..] {
+                        write_value(&self.heap, part, &mut result, &mut vec![]);
+                    }
+                    // The parts stay on the stack until the result is interned.
+                    let result = self.take_string(result);
+                    self.stack.truncate(first_part);
+                    self.push(Value::object(result));
+                }
+                Instruction::GetIndex => {
+                    let value = if let Some(map) = self.as_map(&self.peek(1)) {
+                        let key = self.map_key(self.peek(0))?;
+                        let Some(value) = self.heap.as_map(map).entries.get(key) else {
+                            let key = format_value(&self.heap, &key.value());
+                            let message = format!("Undefined key '{}'.", key);
+                            return Err(self.runtime_error(UndefinedKey, message));
+                        };
+                        value
+                    } else {
+                        let (list, index) = self.list_index(self.peek(1), self.peek(0))?;
+                        self.heap.as_list(list).items[index]
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                Instruction::SetIndex => {
+                    let value = self.peek(0);
+                    if let Some(map) = self.as_map(&self.peek(2)) {
+                        let key = self.map_key(self.peek(1))?;
+                        self.heap.as_map_mut(map).entries.set(key, value);
+                        self.remeasure(map);
+                    } else {
+                        let (list, index) = self.list_index(self.peek(2), self.peek(1))?;
+                        self.heap.as_list_mut(list).items[index] = value;
+                    }
+                    self.pop();
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                Instruction::Method(index) => self.define_method(self.read_string(index.into())),
+                Instruction::MethodLong(index) => self.define_method(self.read_string(index)),
             }
         }
     }
 
-    fn read_byte<T>(&mut self) -> T
-    where
-        T: From<u8>,
-    {
-        let instruction = self.chunk.code[self.ip];
-        self.ip += 1;
-        instruction.into()
+    fn read_instruction(&mut self) -> Result<Instruction, DecodeError> {
+        let instruction = self.current_chunk().decode(self.frame().ip)?;
+        self.frame_mut().ip += instruction.size();
+        Ok(instruction)
+    }
+
+    fn read_constant(&self, index: usize) -> Value {
+        self.current_chunk().constants.values[index]
+    }
+
+    fn read_string(&self, index: u32) -> Key {
+        let string = self.read_constant(index as usize).as_object();
+        self.heap.key(string)
+    }
+
+    fn get_global(&mut self, index: u32) -> Result<(), RuntimeError> {
+        let name = self.read_string(index);
+        let Some(value) = self.globals.get(name) else {
+            return Err(self.undefined_variable(name));
+        };
+        self.push(value);
+        Ok(())
     }
 
-    fn read_constant(&mut self) -> Value {
-        let position = self.read_byte::<u8>();
-        self.chunk.constants.values[position as usize].clone()
+    fn define_global(&mut self, index: u32) {
+        let name = self.read_string(index);
+        self.globals.set(name, self.peek(0));
+        self.pop();
     }
 
-    fn push(&mut self, value: Value) {
+    fn set_global(&mut self, index: u32) -> Result<(), RuntimeError> {
+        let name = self.read_string(index);
+        if self.globals.set(name, self.peek(0)) {
+            self.globals.delete(name);
+            return Err(self.undefined_variable(name));
+        }
+        Ok(())
+    }
+
+    fn get_property(&mut self, index: u32) -> Result<(), RuntimeError> {
+        let Some(instance) = self.as_instance(&self.peek(0)) else {
+            return Err(self.runtime_error(Type, "Only instances have properties."));
+        };
+        let name = self.read_string(index);
+
+        if let Some(value) = self.heap.as_instance(instance).fields.get(name) {
+            self.pop(); // Instance.
+            self.push(value);
+            Ok(())
+        } else {
+            let class = self.heap.as_instance(instance).class;
+            self.bind_method(class, name)
+        }
+    }
+
+    fn set_property(&mut self, index: u32) -> Result<(), RuntimeError> {
+        let Some(instance) = self.as_instance(&self.peek(1)) else {
+            return Err(self.runtime_error(Type, "Only instances have fields."));
+        };
+        let name = self.read_string(index);
+
+        let value = self.peek(0);
+        self.heap.as_instance_mut(instance).fields.set(name, value);
+        self.remeasure(instance);
+        let value = self.pop();
+        self.pop();
+        self.push(value);
+        Ok(())
+    }
+
+    fn get_super(&mut self, index: u32) -> Result<(), RuntimeError> {
+        let name = self.read_string(index);
+        let superclass = self.pop().as_object();
+        self.bind_method(superclass, name)
+    }
+
+    fn super_invoke(&mut self, index: u32, arg_count: u8) -> Result<(), RuntimeError> {
+        let method = self.read_string(index);
+        let superclass = self.pop().as_object();
+        self.invoke_from_class(superclass, method, arg_count)
+    }
+
+    fn closure(&mut self, index: u32) {
+        let function = self.read_constant(index as usize).as_object();
+        // The captures that follow fill in the upvalues.
+        let upvalue_count = self.heap.as_function(function).upvalue_count;
+        let upvalues = Vec::with_capacity(upvalue_count);
+        let closure = self.alloc(Object::Closure(ObjClosure::new(function, upvalues)));
+        self.push(Value::object(closure));
+    }
+
+    fn class(&mut self, index: u32) {
+        let name = self.read_string(index);
+        let name = self.heap.as_string(name.as_string()).chars.clone();
+        let class = self.alloc(Object::Class(ObjClass::new(name)));
+        self.push(Value::object(class));
+    }
+
+    pub(crate) fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
 
-    fn pop(&mut self) -> Value {
+    pub(crate) fn pop(&mut self) -> Value {
         self.stack.pop().unwrap()
     }
 
     fn peek(&self, distance: usize) -> Value {
-        self.stack[self.stack.len() - 1 - distance].clone()
+        self.stack[self.stack.len() - 1 - distance]
     }
 
-    fn is_falsy(value: &Value) -> bool {
-        match value {
-            Value::Nil => true,
-            Value::Boolean(b) => !b,
-            _ => false,
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), RuntimeError> {
+        if callee.is_object() {
+            let object = callee.as_object();
+            match self.heap.get(object) {
+                Object::BoundMethod(bound) => {
+                    let ObjBoundMethod { receiver, method } = *bound;
+                    let slot = self.stack.len() - arg_count as usize - 1;
+                    self.stack[slot] = receiver;
+                    return self.call(method, arg_count);
+                }
+                Object::Class(_) => {
+                    let instance = self.alloc(Object::Instance(ObjInstance::new(object)));
+                    let slot = self.stack.len() - arg_count as usize - 1;
+                    self.stack[slot] = Value::object(instance);
+
+                    if let Some(initializer) =
+                        self.heap.as_class(object).methods.get(self.init_string)
+                    {
+                        return self.call(initializer.as_object(), arg_count);
+                    } else if arg_count != 0 {
+                        let message = format!("Expected 0 arguments but got {}.", arg_count);
+                        return Err(self.runtime_error(Arity, message));
+                    }
+                    return Ok(());
+                }
+                Object::Closure(_) => return self.call(object, arg_count),
+                Object::Native(_) => return self.call_native(object, arg_count),
+                _ => {}
+            }
         }
+
+        Err(self.runtime_error(Type, "Can only call functions and classes."))
     }
 
-    fn concatenate(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
-            (Value::Object(a), Value::Object(b)) => {
-                if let (Object::String(a), Object::String(b)) = (a, b) {
-                    let mut result = a.clone();
-                    result.push_str(&b);
-                    self.push(Value::Object(Object::String(result)));
-                } else {
-                    unreachable!();
+    fn call_native(&mut self, native: ObjRef, arg_count: u8) -> Result<(), RuntimeError> {
+        let arity = self.heap.as_native(native).arity;
+        let function = self.heap.as_native(native).function.clone();
+        if arg_count as usize != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(Arity, message));
+        }
+
+        // Arguments stay on the stack during the call so they remain rooted.
+        let first_arg = self.stack.len() - arg_count as usize;
+        let args = self.stack[first_arg..].to_vec();
+        match function(self, &args) {
+            Ok(result) => match self.check_handle(&result) {
+                Ok(()) => {
+                    self.stack.truncate(first_arg - 1);
+                    self.push(result);
+                    Ok(())
                 }
-            }
+                Err(error) => {
+                    let name = &self.heap.as_native(native).name;
+                    let message = format!("{}() returned an invalid value: {}", name, error);
+                    Err(self.runtime_error(Native, message))
+                }
+            },
+            Err(message) => Err(self.runtime_error(Native, message)),
+        }
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: Key,
+        arg_count: u8,
+    ) -> Result<(), RuntimeError> {
+        let Some(method) = self.heap.as_class(class).methods.get(name) else {
+            return Err(self.undefined_property(name));
+        };
+        self.call(method.as_object(), arg_count)
+    }
+
+    fn invoke(&mut self, name: Key, arg_count: u8) -> Result<(), RuntimeError> {
+        let receiver = self.peek(arg_count as usize);
+        let Some(instance) = self.as_instance(&receiver) else {
+            return Err(self.runtime_error(Type, "Only instances have methods."));
+        };
+
+        let instance = self.heap.as_instance(instance);
+        if let Some(value) = instance.fields.get(name) {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn bind_method(&mut self, class: ObjRef, name: Key) -> Result<(), RuntimeError> {
+        let Some(method) = self.heap.as_class(class).methods.get(name) else {
+            return Err(self.undefined_property(name));
+        };
+
+        let bound = self.alloc(Object::BoundMethod(ObjBoundMethod::new(
+            self.peek(0),
+            method.as_object(),
+        )));
+        self.pop();
+        self.push(Value::object(bound));
+        Ok(())
+    }
+
+    /// Appends a captured upvalue to the closure being built on top of the stack.
+    fn add_capture(&mut self, upvalue: ObjRef) {
+        let closure = self.peek(0).as_object();
+        match self.heap.get_mut(closure) {
+            Object::Closure(closure) => closure.upvalues.push(upvalue),
             _ => unreachable!(),
         }
     }
 
-    fn binary_op(&mut self, op: impl FnOnce(f64, f64) -> Value) {
-        match (self.peek(0), self.peek(1)) {
-            (Value::Number(_), Value::Number(_)) => {
-                let b = self.pop();
-                let a = self.pop();
-                match (a, b) {
-                    (Value::Number(a), Value::Number(b)) => self.push(op(a, b)),
-                    _ => unreachable!(),
-                }
+    fn call(&mut self, closure: ObjRef, arg_count: u8) -> Result<(), RuntimeError> {
+        let function = self.heap.as_closure(closure).function;
+        let arity = self.heap.as_function(function).arity;
+        if arg_count as usize != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(Arity, message));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error(StackOverflow, "Stack overflow."));
+        }
+
+        let slots = self.stack.len() - arg_count as usize - 1;
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots,
+        });
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let position = self
+            .open_upvalues
+            .partition_point(|&upvalue| self.heap.as_upvalue(upvalue).location < location);
+
+        if let Some(&upvalue) = self.open_upvalues.get(position) {
+            if self.heap.as_upvalue(upvalue).location == location {
+                return upvalue;
             }
-            _ => {
-                self.runtime_error("Operands must be numbers.");
-                return;
+        }
+
+        let created = self.alloc(Object::Upvalue(ObjUpvalue::new(location)));
+        self.open_upvalues.insert(position, created);
+        created
+    }
+
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let upvalue = self.heap.as_upvalue_mut(upvalue);
+            if upvalue.location < last {
+                break;
             }
+            upvalue.closed = Some(self.stack[upvalue.location]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn is_falsy(value: &Value) -> bool {
+        value.is_nil() || (value.is_bool() && !value.as_bool_unchecked())
+    }
+
+    fn define_method(&mut self, name: Key) {
+        let method = self.peek(0);
+        let class = self.peek(1).as_object();
+        self.heap.as_class_mut(class).methods.set(name, method);
+        self.remeasure(class);
+        self.pop();
+    }
+
+    fn as_instance(&self, value: &Value) -> Option<ObjRef> {
+        if value.is_object() && matches!(self.heap.get(value.as_object()), Object::Instance(_)) {
+            Some(value.as_object())
+        } else {
+            None
+        }
+    }
+
+    fn as_map(&self, value: &Value) -> Option<ObjRef> {
+        if value.is_object() && matches!(self.heap.get(value.as_object()), Object::Map(_)) {
+            Some(value.as_object())
+        } else {
+            None
+        }
+    }
+
+    /// Hashes `value` for use as a map key, failing if it can't be one.
+    fn map_key(&self, value: Value) -> Result<Key, RuntimeError> {
+        Key::from_value(&self.heap, value)
+            .map_err(|message| self.runtime_error(InvalidKey, message))
+    }
+
+    /// Checks that `list[index]` is in bounds.
+    fn list_index(&self, list: Value, index: Value) -> Result<(ObjRef, usize), RuntimeError> {
+        if !list.is_object() || !matches!(self.heap.get(list.as_object()), Object::List(_)) {
+            return Err(self.runtime_error(Type, "Can only index lists and maps."));
+        }
+        if !index.is_number() || index.as_number_unchecked().fract() != 0.0 {
+            return Err(self.runtime_error(Index, "List index must be an integer."));
+        }
+
+        let list = list.as_object();
+        let len = self.heap.as_list(list).items.len();
+        let index = index.as_number_unchecked();
+        if index < 0.0 || index >= len as f64 {
+            let message = format!("List index {} out of bounds for length {}.", index, len);
+            return Err(self.runtime_error(Index, message));
+        }
+        Ok((list, index as usize))
+    }
+
+    fn is_string(&self, value: &Value) -> bool {
+        value.is_object() && matches!(self.heap.get(value.as_object()), Object::String(_))
+    }
+
+    fn concatenate(&mut self) {
+        let b = self.pop().as_object();
+        let a = self.pop().as_object();
+        let mut result = self.heap.as_string(a).chars.clone();
+        result.push_str(&self.heap.as_string(b).chars);
+        let result = self.take_string(result);
+        self.push(Value::object(result));
+    }
+
+    fn binary_op(&mut self, op: impl FnOnce(f64, f64) -> Value) -> Result<(), RuntimeError> {
+        if !self.peek(0).is_number() || !self.peek(1).is_number() {
+            return Err(self.runtime_error(Type, "Operands must be numbers."));
         }
+
+        let b = self.pop().as_number_unchecked();
+        let a = self.pop().as_number_unchecked();
+        self.push(op(a, b));
+        Ok(())
+    }
+
+    fn undefined_variable(&self, name: Key) -> RuntimeError {
+        let name = &self.heap.as_string(name.as_string()).chars;
+        let message = format!("Undefined variable '{}'.", name);
+        self.runtime_error(UndefinedVariable, message)
+    }
+
+    fn undefined_property(&self, name: Key) -> RuntimeError {
+        let name = &self.heap.as_string(name.as_string()).chars;
+        let message = format!("Undefined property '{}'.", name);
+        self.runtime_error(UndefinedProperty, message)
+    }
+
+    /// Builds an error for the current instruction, capturing the call stack.
+    fn runtime_error(&self, kind: RuntimeErrorKind, message: impl Into<String>) -> RuntimeError {
+        let stack_trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| StackFrame {
+                function: self.heap.as_function(frame.function).name.clone(),
+                span: self.frame_span(frame),
+            })
+            .collect();
+        RuntimeError {
+            kind,
+            message: message.into(),
+            span: self.frame_span(self.frame()),
+            stack_trace,
+        }
+    }
+
+    /// Where in the source `frame` is currently executing.
+    fn frame_span(&self, frame: &CallFrame) -> Span {
+        let function = self.heap.as_function(frame.function);
+        function.chunk.get_span(frame.ip - 1)
+    }
+}
+
+/// Marks every object the VM keeps alive outside of the heap itself.
+fn mark_roots(
+    heap: &mut Heap,
+    stack: &[Value],
+    frames: &[CallFrame],
+    globals: &Table,
+    open_upvalues: &[ObjRef],
+    scripts: &[Weak<ObjRef>],
+    init_string: Key,
+) {
+    for &slot in stack {
+        heap.mark_value(slot);
+    }
+
+    for frame in frames {
+        heap.mark_object(frame.closure);
+    }
+
+    for &upvalue in open_upvalues {
+        heap.mark_object(upvalue);
+    }
+
+    for script in scripts.iter().filter_map(Weak::upgrade) {
+        heap.mark_object(*script);
+    }
+
+    heap.mark_table(globals);
+    heap.mark_object(init_string.as_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::convert::FromLox;
+
+    /// An output that tests can read back after handing it to a `Vm`.
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs `source` in `vm`, returning everything it printed.
+    fn output_of(mut vm: Vm, source: &str) -> String {
+        let buffer = Buffer::default();
+        vm.set_output(buffer.clone());
+        vm.interpret(source).unwrap();
+        let bytes = buffer.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn output(source: &str) -> String {
+        output_of(Vm::new(), source)
+    }
+
+    fn runtime_error(source: &str) -> RuntimeError {
+        match Vm::new().interpret(source) {
+            Err(LoxError::Runtime(error)) => error,
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_carry_their_kind_and_line() {
+        let error = runtime_error("var a = 1;\nprint a - \"b\";");
+        assert_eq!(error.kind, RuntimeErrorKind::Type);
+        assert_eq!(error.message, "Operands must be numbers.");
+        assert_eq!(error.line(), 2);
+
+        assert_eq!(runtime_error("print x;").kind, UndefinedVariable);
+        assert_eq!(runtime_error("print {}[1];").kind, UndefinedKey);
+        assert_eq!(runtime_error("print [1][1];").kind, Index);
+        assert_eq!(runtime_error("fun f() {} f(1);").kind, Arity);
+        assert_eq!(runtime_error("fun f() { f(); } f();").kind, StackOverflow);
+        assert_eq!(runtime_error("len(1);").kind, Native);
+    }
+
+    #[test]
+    fn the_first_error_stops_the_script() {
+        let mut vm = Vm::new();
+        let error = vm.interpret("var a = 1 - \"b\";\nvar b = 2;");
+        assert!(matches!(error, Err(LoxError::Runtime(_))));
+        // Neither definition ran, and the VM is usable again.
+        assert!(matches!(
+            vm.interpret("print a;"),
+            Err(LoxError::Runtime(_))
+        ));
+        assert!(matches!(
+            vm.interpret("print b;"),
+            Err(LoxError::Runtime(_))
+        ));
+        assert_eq!(output_of(vm, "var c = 3; print c;"), "3\n");
+    }
+
+    #[test]
+    fn stack_trace_lists_calls_innermost_first() {
+        let error = runtime_error("fun inner() { nil(); }\nfun outer() {\n  inner();\n}\nouter();");
+        let trace: Vec<(Option<&str>, usize)> = error
+            .stack_trace
+            .iter()
+            .map(|frame| (frame.function.as_deref(), frame.span.line))
+            .collect();
+        assert_eq!(trace, [(Some("inner"), 1), (Some("outer"), 3), (None, 5)]);
+
+        let diagnostic = error.to_diagnostic("test.lox");
+        assert_eq!(diagnostic.span.line, 1);
+        assert_eq!(
+            diagnostic.notes,
+            ["test.lox:3:8 in outer()", "test.lox:5:6 in script"]
+        );
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        eprintln!("{}", message);
+    #[test]
+    fn stack_overflow_collapses_repeated_frames() {
+        let error = runtime_error("fun f() {\n  f();\n}\nf();");
+        assert_eq!(error.kind, StackOverflow);
+        assert_eq!(error.stack_trace.len(), FRAMES_MAX);
 
-        let instruction = self.ip - 1;
-        let line = self.chunk.lines[instruction] as usize;
-        eprintln!("[line {}] in script", line);
+        let diagnostic = error.to_diagnostic("test.lox");
+        assert_eq!(
+            diagnostic.notes,
+            [
+                "test.lox:2:4 in f()",
+                "... repeated 61 more times",
+                "test.lox:4:2 in script"
+            ]
+        );
+        assert!(diagnostic.help.is_some());
+    }
+
+    #[test]
+    fn compiled_scripts_survive_collections() {
+        let mut vm = Vm::builder().gc_stress(true).build();
+        let script = vm.compile("var n = 0; n = n + 1;").unwrap();
+        // Allocate plenty in between to give the collector a chance.
+        vm.interpret("var s = \"\"; for (var i = 0; i < 10; i = i + 1) s = s + \"x\";")
+            .unwrap();
+
+        let instructions: Vec<Instruction> = vm
+            .chunk(&script)
+            .unwrap()
+            .instructions()
+            .map(|decoded| decoded.unwrap().1)
+            .collect();
+        assert_eq!(instructions.last(), Some(&Instruction::Return));
+
+        vm.run(&script).unwrap();
+        vm.run(&script).unwrap();
+        // Calling nil fails, so this only passes if the script really ran.
+        assert!(vm.interpret("if (n != 1) nil();").is_ok());
+    }
+
+    #[test]
+    fn calls_functions_and_returns_values() {
+        let source = "\
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+print fib(10);
+
+fun noReturn() {}
+print noReturn();
+
+fun early(n) {
+  while (true) {
+    if (n > 3) return n;
+    n = n + 1;
+  }
+}
+print early(0);
+
+fun add(a, b, c) { return a + b + c; }
+var alias = add;
+print alias(1, 2, 3);
+print add;
+print len;
+";
+        assert_eq!(output(source), "55\nnil\n4\n6\n<fn add>\n<native fn len>\n");
+    }
+
+    #[test]
+    fn call_errors() {
+        let error = runtime_error("fun f(a, b) {}\nf(1);");
+        assert_eq!(error.kind, Arity);
+        assert_eq!(error.message, "Expected 2 arguments but got 1.");
+        assert_eq!(error.line(), 2);
+
+        let error = runtime_error("var notAFunction = 1;\nnotAFunction();");
+        assert_eq!(error.kind, Type);
+        assert_eq!(error.message, "Can only call functions and classes.");
+    }
+
+    #[test]
+    fn closures_capture_variables() {
+        let source = "\
+fun makeCounter() {
+  var count = 0;
+  fun counter() {
+    count = count + 1;
+    return count;
+  }
+  return counter;
+}
+var a = makeCounter();
+var b = makeCounter();
+a();
+print a();
+print b();
+
+var get;
+var set;
+{
+  var shared = \"before\";
+  fun g() { return shared; }
+  fun s(value) { shared = value; }
+  get = g;
+  set = s;
+}
+set(\"after\");
+print get();
+
+fun outer() {
+  var x = \"outer\";
+  fun middle() {
+    fun inner() { return x; }
+    return inner;
+  }
+  return middle;
+}
+print outer()()();
+";
+        assert_eq!(output(source), "2\n1\nafter\nouter\n");
+    }
+
+    #[test]
+    fn closures_in_a_loop() {
+        let source = "\
+var byLoopVariable = [];
+var byCopy = [];
+for (var i = 0; i < 3; i = i + 1) {
+  fun loopVariable() { return i; }
+  append(byLoopVariable, loopVariable);
+
+  var j = i;
+  fun copy() { return j; }
+  append(byCopy, copy);
+}
+for (var k = 0; k < 3; k = k + 1) {
+  print \"${byLoopVariable[k]()} ${byCopy[k]()}\";
+}
+";
+        // The loop variable is one variable shared by every iteration, while
+        // each iteration declares its own `j`.
+        assert_eq!(output(source), "3 0\n3 1\n3 2\n");
+    }
+
+    #[test]
+    fn collecting_garbage_does_not_change_output() {
+        let source = "\
+class Node {
+  init(value, next) {
+    this.value = value;
+    this.next = next;
+  }
+}
+fun makeAdder(n) {
+  fun add(x) { return x + n; }
+  return add;
+}
+var list = nil;
+var adders = [];
+for (var i = 0; i < 20; i = i + 1) {
+  list = Node(\"node \" + \"${i}\", list);
+  append(adders, makeAdder(i));
+  var garbage = {\"key\": [i, \"garbage\" + \"string\"]};
+}
+print list.value;
+print list.next.next.value;
+print adders[5](100);
+print {\"a\": [1, \"b\" + \"c\"]};
+";
+        let expected = "node 19\nnode 17\n105\n{a: [1, bc]}\n";
+        assert_eq!(output(source), expected);
+        assert_eq!(
+            output_of(Vm::builder().gc_stress(true).build(), source),
+            expected
+        );
+    }
+
+    #[test]
+    fn classes_with_methods_and_initializers() {
+        let source = "\
+class Point {
+  init(x, y) {
+    this.x = x;
+    this.y = y;
+  }
+  sum() { return this.x + this.y; }
+}
+var p = Point(1, 2);
+print p.sum();
+p.x = 10;
+var sum = p.sum;
+print sum();
+print p.init(3, 4) == p;
+print p.x;
+print Point;
+print p;
+
+// Fields shadow methods.
+fun double() { return 2 * p.y; }
+p.sum = double;
+print p.sum();
+";
+        assert_eq!(output(source), "3\n12\ntrue\n3\nPoint\nPoint instance\n8\n");
+    }
+
+    #[test]
+    fn subclasses_inherit_and_call_super() {
+        let source = "\
+class A {
+  init(name) { this.name = name; }
+  greet() { return \"A says hi to \" + this.name; }
+  who() { return \"A\"; }
+}
+class B < A {
+  init(name) { super.init(name + \"!\"); }
+  greet() { return \"B, then \" + super.greet(); }
+  who() {
+    var method = super.who;
+    return method() + \"B\";
+  }
+}
+class C < B {}
+var c = C(\"c\");
+print c.greet();
+print c.who();
+print c.name;
+";
+        assert_eq!(output(source), "B, then A says hi to c!\nAB\nc!\n");
+    }
+
+    #[test]
+    fn class_errors() {
+        let error = runtime_error("class A {}\nA().missing();");
+        assert_eq!(error.kind, UndefinedProperty);
+        assert_eq!(error.message, "Undefined property 'missing'.");
+        assert_eq!(error.line(), 2);
+
+        assert_eq!(runtime_error("var x = 1;\nclass A < x {}").kind, Type);
+        assert_eq!(runtime_error("class A {}\nA(1);").kind, Arity);
+        assert_eq!(runtime_error("1.field;").kind, Type);
+    }
+
+    #[test]
+    fn lists_support_indexing_and_natives() {
+        let source = "\
+var list = [1, \"two\", nil];
+print list;
+print list[1];
+list[2] = [3, 4];
+print list[2][1];
+print len(list);
+
+append(list, 5);
+insert(list, 0, 0);
+print list;
+print remove(list, 1);
+print remove(list, len(list) - 1);
+print list;
+print slice(list, 1, 3);
+print [];
 
-        self.reset_stack();
+var cycle = [];
+append(cycle, cycle);
+print cycle;
+";
+        assert_eq!(
+            output(source),
+            "[1, two, nil]\ntwo\n4\n3\n[0, 1, two, [3, 4], 5]\n1\n5\n\
+             [0, two, [3, 4]]\n[two, [3, 4]]\n[]\n[[...]]\n"
+        );
+    }
+
+    #[test]
+    fn list_index_errors() {
+        let error = runtime_error("var list = [1, 2];\nprint list[2];");
+        assert_eq!(error.kind, Index);
+        assert_eq!(error.message, "List index 2 out of bounds for length 2.");
+        assert_eq!(error.line(), 2);
+
+        let error = runtime_error("[1][-1] = 0;");
+        assert_eq!(error.message, "List index -1 out of bounds for length 1.");
+        let error = runtime_error("print [1][0.5];");
+        assert_eq!(error.message, "List index must be an integer.");
+        assert_eq!(runtime_error("print 1[0];").kind, Type);
+
+        let error = runtime_error("remove([], 0);");
+        assert_eq!(error.kind, Native);
+        assert_eq!(error.message, "List index 0 out of bounds for length 0.");
+        let error = runtime_error("append(nil, 1);");
+        assert_eq!(error.message, "append() expects a list.");
+    }
+
+    #[test]
+    fn maps_support_keys_and_natives() {
+        let source = "\
+var map = {\"name\": \"rlox\", 1: \"one\", true: \"yes\", nil: \"nothing\"};
+print map[\"name\"];
+print map[1];
+print map[true];
+print map[nil];
+print len(map);
+
+// 0 and -0 are equal, so they are the same key.
+var zero = {0: \"zero\"};
+zero[-0] = \"still zero\";
+print len(zero);
+print zero[0];
+
+print has(map, \"name\");
+print delete(map, \"name\");
+print delete(map, \"name\");
+print has(map, \"name\");
+print len(map);
+
+var one = {\"a\": 1};
+print keys(one);
+print values(one);
+print entries(one);
+print {};
+print len(keys({}));
+
+var cycle = {};
+cycle[\"self\"] = cycle;
+print cycle;
+";
+        assert_eq!(
+            output(source),
+            "rlox\none\nyes\nnothing\n4\n1\nstill zero\n\
+             true\ntrue\nfalse\nfalse\n3\n[a]\n[1]\n[[a, 1]]\n{}\n0\n{self: {...}}\n"
+        );
+    }
+
+    #[test]
+    fn map_key_errors() {
+        let error = runtime_error("var map = {};\nprint map[\"x\"];");
+        assert_eq!(error.kind, UndefinedKey);
+        assert_eq!(error.message, "Undefined key 'x'.");
+        assert_eq!(error.line(), 2);
+
+        let error = runtime_error("var map = {};\nmap[0 / 0] = 1;");
+        assert_eq!(error.kind, InvalidKey);
+        assert_eq!(error.message, "Map keys can't be NaN.");
+        assert_eq!(error.line(), 2);
+        assert_eq!(runtime_error("print {0 / 0: 1};").kind, InvalidKey);
+
+        let error = runtime_error("print {[1]: 2};");
+        assert_eq!(error.kind, InvalidKey);
+        assert_eq!(
+            error.message,
+            "Map keys must be strings, numbers, booleans or nil."
+        );
+        assert_eq!(runtime_error("print {}[{}];").kind, InvalidKey);
+
+        let error = runtime_error("has({}, []);");
+        assert_eq!(error.kind, Native);
+        assert_eq!(
+            error.message,
+            "Map keys must be strings, numbers, booleans or nil."
+        );
+        assert_eq!(runtime_error("delete({}, 0 / 0);").kind, Native);
+        let error = runtime_error("keys([]);");
+        assert_eq!(error.message, "keys() expects a map.");
+    }
+
+    #[test]
+    fn runs_code_past_the_first_256_constants() {
+        let mut source: String = (0..300).map(|i| format!("{};\n", i)).collect();
+        source.push_str(
+            "fun f() { return 1; }
+class A { m(n) { return n + 1; } }
+class B < A {
+  m(n) { return super.m(n) + 1; }
+  g() { var m = super.m; return m(0); }
+}
+var b = B();
+b.x = f();
+var y = b.m(b.x) + b.g();
+y = y + 1;
+// Calling nil fails, so this only passes if every step worked.
+if (y != 5) nil();
+",
+        );
+        assert!(Vm::new().interpret(&source).is_ok());
+    }
+
+    #[test]
+    fn dropped_scripts_are_released() {
+        let mut vm = Vm::builder().gc_stress(true).build();
+        let dropped = vm.compile("var a = 1;").unwrap();
+        let script = vm.compile("var b = 2;").unwrap();
+        let clone = script.clone();
+        drop(dropped);
+        drop(script);
+
+        vm.compile("var c = 3;").unwrap();
+        assert_eq!(vm.scripts.len(), 2);
+        // The clone still roots its function through the collections above.
+        vm.run(&clone).unwrap();
+        assert!(vm.interpret("if (b != 2) nil();").is_ok());
+    }
+
+    #[test]
+    fn handles_from_another_vm_are_rejected() {
+        let mut a = Vm::new();
+        let mut b = Vm::new();
+        let script = a.compile("var x = 1;").unwrap();
+        assert!(matches!(b.run(&script), Err(LoxError::ForeignHandle)));
+        assert!(matches!(b.chunk(&script), Err(LoxError::ForeignHandle)));
+
+        let kept = Rc::new(RefCell::new(Value::nil()));
+        let slot = kept.clone();
+        a.define_function("keep", move |value: Value| *slot.borrow_mut() = value);
+        a.interpret("keep(\"from a\");").unwrap();
+        let value = *kept.borrow();
+        assert_eq!(a.format_value(&value).unwrap(), "from a");
+        assert!(matches!(
+            b.format_value(&value),
+            Err(LoxError::ForeignHandle)
+        ));
+
+        b.define_function("leak", move || value);
+        let Err(LoxError::Runtime(error)) = b.interpret("leak();") else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(error.kind, Native);
+        assert_eq!(
+            error.message,
+            "leak() returned an invalid value: Handle belongs to a different Vm."
+        );
+    }
+
+    #[test]
+    fn values_kept_by_the_host_are_checked_after_a_collection() {
+        let mut vm = Vm::builder().gc_stress(true).build();
+        let kept = Rc::new(RefCell::new(vec![]));
+        let slot = kept.clone();
+        vm.define_function("keep", move |value: Value| slot.borrow_mut().push(value));
+        vm.interpret("keep([\"dropped\"]);\nvar list = [\"global\"];\nkeep(list);")
+            .unwrap();
+        // Every allocation collects, so the first list's slot gets reused.
+        vm.interpret("var a = [\"z\"]; var b = [a, a];").unwrap();
+
+        let (dropped, global) = (kept.borrow()[0], kept.borrow()[1]);
+        assert!(matches!(
+            vm.format_value(&dropped),
+            Err(LoxError::StaleHandle)
+        ));
+        assert_eq!(vm.format_value(&global).unwrap(), "[global]");
+    }
+
+    /// Defines `eval(source)`, which runs `source` on the calling VM, and
+    /// `try_eval(source)`, which reports whether that run succeeded.
+    fn define_eval(vm: &mut Vm) {
+        vm.define_native("eval", 1, |vm, args| {
+            let source = String::from_lox(vm, args[0]).ok_or("eval() expects a string.")?;
+            vm.interpret(&source).map_err(|error| error.to_string())
+        });
+        vm.define_native("try_eval", 1, |vm, args| {
+            let source = String::from_lox(vm, args[0]).ok_or("try_eval() expects a string.")?;
+            Ok(Value::boolean(vm.interpret(&source).is_ok()))
+        });
+    }
+
+    #[test]
+    fn natives_can_run_nested_scripts() {
+        let mut vm = Vm::new();
+        define_eval(&mut vm);
+        let source = "\
+fun f(a) {
+  var b = \"local\";
+  eval(\"print \\\"inner\\\";\");
+  print a + \" \" + b;
+}
+print \"before\";
+f(\"outer\");
+print \"after\";
+";
+        assert_eq!(output_of(vm, source), "before\ninner\nouter local\nafter\n");
+    }
+
+    #[test]
+    fn nested_scripts_can_reenter_the_caller() {
+        let mut vm = Vm::new();
+        define_eval(&mut vm);
+        let source = "\
+var depth = 0;
+fun down() {
+  depth = depth + 1;
+  var mine = depth;
+  if (mine < 3) eval(\"down();\");
+  print mine;
+}
+down();
+
+var a = \"kept\";
+fun g() {
+  var b = \"local\";
+  print try_eval(\"fun h() { nil(); } h();\");
+  print b;
+}
+g();
+print a;
+";
+        assert_eq!(output_of(vm, source), "3\n2\n1\nfalse\nlocal\nkept\n");
+
+        let mut vm = Vm::new();
+        define_eval(&mut vm);
+        let script = vm.compile("eval(\"nil();\");").unwrap();
+        let Err(LoxError::Runtime(error)) = vm.run(&script) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(error.kind, Native);
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        assert!(vm.run(&script).is_err());
+        assert!(vm.interpret("var x = 1;").is_ok());
+    }
+
+    #[test]
+    fn compile_errors_are_diagnostics() {
+        let Err(LoxError::Compile(diagnostics)) = Vm::new().interpret("print 1") else {
+            panic!("expected a compile error");
+        };
+        assert_eq!(diagnostics[0].message, "Expect ';' after value.");
     }
 }